@@ -1,3 +1,27 @@
+use std::process::Command;
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // Embed build metadata so the running image can be traced back to its artifact
+    let git_hash = command_output("git", &["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let build_timestamp = command_output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=FW_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=FW_BUILD_TIMESTAMP={}", build_timestamp);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
 }
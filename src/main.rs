@@ -4,29 +4,130 @@
 #![no_main]
 
 use esp_idf_sys::*;
-use esp_idf_hal::{delay::Ets, i2c::I2cDriver, peripherals::Peripherals, prelude::*};
+use esp_idf_hal::{
+    delay::Ets,
+    gpio::AnyIOPin,
+    i2c::{I2cDriver, I2C0},
+    peripherals::Peripherals,
+    prelude::*,
+    task::CriticalSection,
+};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    nvs::EspDefaultNvsPartition,
+    nvs::{EspDefaultNvs, EspDefaultNvsPartition, EspNvs},
     wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
-    ipv4::IpInfo
+    ipv4::{IpInfo, Ipv4Addr},
+    ping::{Configuration as PingConfiguration, EspPing},
+    sntp::{EspSntp, SyncStatus},
 };
-use bme280::i2c::BME280;
-use log::{info, error};
+use bme280::{i2c::BME280, Configuration as Bme280Configuration, IIRFilter, Oversampling};
+use log::{debug, info, warn, error};
 use anyhow::{Result, anyhow};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use alloc::{boxed::Box, string::{String, ToString}, ffi::CString, format, vec::Vec};
-use core::ffi::c_void;
+use core::cell::UnsafeCell;
+use core::ffi::{c_char, c_void, CStr};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embedded_hal::delay::DelayNs;
 use sha2::{Digest, Sha256};
 extern crate alloc;
 
+mod options;
+mod version;
+use options::{
+    AlarmDirection, AlarmMetric, BacklogFlushOrder, Bme280Mode, ChecksumMode, Co2Compensation, Co2ReadErrorReport,
+    GasSensorType, SinkFormat, TelemetryEncoding, UnknownTopicPolicy,
+};
+use version::{is_version_downgrade, parse_version};
+
+// Site-specific settings. Building firmware for another site means editing DEFAULT_CONFIG and nothing else.
+// Values stored at runtime take precedence over it: setBroker replaces the broker settings and setLocation the
+// coordinates, both kept in NVS across reboots.
+struct DeviceConfig {
+    // WiFi credentials; the driver holds them in fixed buffers of 32 (SSID) and 64 (password) bytes
+    wifi_ssid: &'static str,
+    wifi_password: &'static str,
+    mqtt_broker_url: &'static str,
+    mqtt_username: &'static str,
+    mqtt_password: &'static str,
+    // Explicit MQTT client id; None gives every flashed unit "<MQTT_CLIENT_ID_PREFIX><station MAC>"
+    mqtt_client_id: Option<&'static str>,
+    // Device location reported with every reading
+    latitude: f64,
+    longitude: f64,
+    sensor_sample_interval_ms: u32,
+    // Firmware info poll cadence, independent of the sampling interval
+    firmware_info_poll_interval_ms: u32,
+    // BME280 bus; the pins are also bit-banged by I2C bus recovery
+    i2c_sda_gpio: gpio_num_t,
+    i2c_scl_gpio: gpio_num_t,
+    i2c_baudrate_khz: u32,
+    // MQ-135 analog output, on ADC2
+    co2_adc_channel: adc_channel_t,
+    // Rollout group this unit belongs to, matched against the fw_tag shared attribute and reported as
+    // current_fw_tag. None accepts only firmware without a group tag.
+    fw_tag: Option<&'static str>,
+}
+
+const DEFAULT_CONFIG: DeviceConfig = DeviceConfig {
+    wifi_ssid: "GRATIS",
+    wifi_password: "Gakgratis",
+    mqtt_broker_url: "mqtt://mqtt.thingsboard.cloud:1883",
+    mqtt_username: "nazwana",
+    mqtt_password: "akuandik08",
+    mqtt_client_id: Some("eprtrartn5tpdw7oq38f"),
+    latitude: -7.278306,
+    longitude: 112.792028,
+    sensor_sample_interval_ms: 5000,
+    firmware_info_poll_interval_ms: 30000,
+    i2c_sda_gpio: 8,
+    i2c_scl_gpio: 9,
+    i2c_baudrate_khz: 100,
+    co2_adc_channel: adc_channel_t_ADC_CHANNEL_1,
+    fw_tag: None,
+};
+
+// Remote firmware updates. When disabled the device never polls for firmware info, does not subscribe to the
+// attribute and firmware topics, and reports fw_state "DISABLED" once after connecting.
+const OTA_ENABLED: bool = true;
+
 // OTA Constants
 const OTA_REQUEST_TOPIC: &str = "v1/devices/me/attributes/request/";
-const OTA_RESPONSE_TOPIC: &str = "v1/devices/me/attributes/response/";
-const OTA_FIRMWARE_REQUEST_TOPIC: &str = "v2/fw/request";
-const OTA_FIRMWARE_RESPONSE_TOPIC: &str = "v2/fw/response";
 const OTA_TELEMETRY_TOPIC: &str = "v1/devices/me/telemetry";
 
+// Topic for OTA progress reports (state, progress, throughput, errors). None keeps them on the telemetry topic
+// next to the sensor readings; on ThingsBoard, Some("v1/devices/me/attributes") stores them as client
+// attributes instead, outside the sensor time series.
+const OTA_PROGRESS_TOPIC: Option<&str> = None;
+
+// RPC Constants
+const RPC_REQUEST_TOPIC: &str = "v1/devices/me/rpc/request/";
+const RPC_RESPONSE_TOPIC: &str = "v1/devices/me/rpc/response/";
+
+// Subscription patterns, also used to route incoming messages to their handlers
+const OTA_RESPONSE_SUBSCRIPTION: &str = "v1/devices/me/attributes/response/+";
+const ATTRIBUTES_TOPIC: &str = "v1/devices/me/attributes";
+const RPC_REQUEST_SUBSCRIPTION: &str = "v1/devices/me/rpc/request/+";
+
+// Subscription QoS per topic. Firmware chunks and RPC commands must not be lost; attribute pushes are re-read
+// by the periodic firmware info poll anyway. ThingsBoard grants at most QoS 1, so QoS 2 only helps on brokers
+// that support it. Downgrades by the broker are logged when the subscription is acknowledged.
+const OTA_RESPONSE_QOS: i32 = 1;
+const ATTRIBUTES_QOS: i32 = 0;
+const FIRMWARE_RESPONSE_QOS: i32 = 1;
+const RPC_REQUEST_QOS: i32 = 1;
+// Publish QoS for sensor telemetry; 0 saves the PUBACK round trip at high sampling rates
+const TELEMETRY_QOS: i32 = 1;
+
+// Firmware chunk topics; {id} and {index} must each fill a whole topic level.
+// The response subscription is derived by replacing both placeholders with `+`.
+const OTA_CHUNK_REQUEST_TOPIC_TEMPLATE: &str = "v2/fw/request/{id}/chunk/{index}";
+const OTA_CHUNK_RESPONSE_TOPIC_TEMPLATE: &str = "v2/fw/response/{id}/chunk/{index}";
+
+// What to do with messages on topics that have no registered handler
+const UNKNOWN_TOPIC_POLICY: UnknownTopicPolicy = UnknownTopicPolicy::Log;
+
 // OTA Shared Attributes
 const FW_TITLE_ATTR: &str = "fw_title";
 const FW_VERSION_ATTR: &str = "fw_version";
@@ -34,19 +135,506 @@ const FW_SIZE_ATTR: &str = "fw_size";
 const FW_CHECKSUM_ATTR: &str = "fw_checksum";
 const FW_CHECKSUM_ALG_ATTR: &str = "fw_checksum_algorithm";
 const FW_STATE_ATTR: &str = "fw_state";
+const FW_FORCE_UPDATE_ATTR: &str = "fw_force_update";
+// Optional direct download URL; when present the image is streamed over HTTP(S) instead of MQTT chunks
+const FW_URL_ATTR: &str = "fw_url";
+// Optional rollout tag; firmware tagged for another group is skipped. ThingsBoard fills in "<title> <version>"
+// when a package has no tag of its own, which counts as untagged.
+const FW_TAG_ATTR: &str = "fw_tag";
+
+// HTTP(S) OTA transport: socket timeout and how much of the image is read per main loop pass
+const OTA_HTTP_TIMEOUT_MS: i32 = 10000;
+const OTA_HTTP_BYTES_PER_POLL: usize = 16 * 1024;
+
+// Attribute payloads above this size are rejected before they are parsed
+const MAX_ATTRIBUTE_PAYLOAD_BYTES: usize = 2048;
+
+// OTA persistent state (NVS)
+const OTA_NVS_NAMESPACE: &str = "ota";
+const NVS_FORCED_CHECKSUM_KEY: &str = "forced_sha";
+
+// Lifetime statistics (NVS): counted in RAM and written back at most this often to limit flash wear,
+// then reported as telemetry
+const STATS_NVS_NAMESPACE: &str = "stats";
+const STATS_FLUSH_INTERVAL_MS: u32 = 15 * 60 * 1000;
+
+// Device location set by setLocation; DEFAULT_CONFIG's coordinates apply until one is stored
+const LOCATION_NVS_NAMESPACE: &str = "location";
+
+// Firmware chunk size requested over MQTT; a chunk plus the margin must fit the MQTT buffer
+const OTA_CHUNK_SIZE: usize = 4096;
+// Slack allowed on top of chunk_size when validating advertised firmware response lengths
+const CHUNK_SIZE_MARGIN: usize = 64;
+
+// Largest fw_size accepted before anything is erased; None uses the size of the target OTA partition
+const MAX_FW_SIZE: Option<u32> = None;
+
+// Below this WiFi RSSI a new update is deferred (fw_state "DEFERRED_WEAK_SIGNAL") instead of starting a download
+// that would likely fail; it starts on a later firmware info poll once the signal is back. None never defers.
+const OTA_MIN_RSSI_DBM: Option<i8> = Some(-80);
+
+// The UPDATED report is published at QoS 1; before restarting, the broker's acknowledgment is awaited this long
+const OTA_FINAL_REPORT_ACK_WAIT_MS: u32 = 5000;
+
+// Compare the version embedded in the downloaded image (esp_app_desc_t) with the advertised fw_version before
+// switching the boot partition. Only enable this when build-ota.sh stamps the image with the dashboard version.
+const OTA_VERIFY_IMAGE_VERSION: bool = false;
+
+// How the image SHA-256 is computed. Streaming hashes each chunk as it is written and needs almost no RAM.
+// Buffered keeps the whole image in RAM and hashes it once the download ends; it needs fw_size bytes of free
+// heap, and a download is refused up front when that cannot be reserved.
+const OTA_CHECKSUM_MODE: ChecksumMode = ChecksumMode::Streaming;
+
+// Post-OTA validation (needs CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE): a freshly installed image is only marked
+// valid after this many consecutive successful telemetry publishes and this much uptime. If it has not proven
+// itself by the deadline, it is marked invalid and the bootloader rolls back to the previous image.
+const OTA_VALIDATION_MIN_PUBLISHES: u32 = 10;
+const OTA_VALIDATION_MIN_UPTIME_MS: u32 = 5 * 60 * 1000;
+const OTA_VALIDATION_DEADLINE_MS: u32 = 30 * 60 * 1000;
+const OTA_VALIDATION_REPORT_INTERVAL_MS: u32 = 30000;
+
+// OTA erase verification: number of regions read back after erasing (0 disables) and bytes per region
+const ERASE_VERIFY_SAMPLES: u32 = 16;
+const ERASE_VERIFY_SAMPLE_SIZE: usize = 256;
+
+// OTA download rate: EMA weight of each new chunk sample, and samples needed before an ETA is reported
+const OTA_THROUGHPUT_EMA_ALPHA: f32 = 0.2;
+const OTA_ETA_MIN_SAMPLES: u32 = 3;
+
+// Chunk re-request timeout: time to move one chunk at the observed rate (or the assumed rate before one
+// is measured), times the margin, clamped to the floor and ceiling
+const CHUNK_TIMEOUT_FLOOR_MS: u32 = 5000;
+const CHUNK_TIMEOUT_CEILING_MS: u32 = 60000;
+const CHUNK_TIMEOUT_MARGIN: f32 = 3.0;
+const CHUNK_TIMEOUT_ASSUMED_BPS: f32 = 2048.0;
+
+// WiFi credential limits of the driver's fixed buffers
+const WIFI_SSID_MAX_BYTES: usize = 32;
+const WIFI_PASSWORD_MAX_BYTES: usize = 64;
+
+// WiFi connection: DHCP wait bound, progress log cadence and connect attempts at boot
+const WIFI_NETIF_UP_TIMEOUT_MS: u32 = 30000;
+const WIFI_PROGRESS_LOG_INTERVAL_MS: u32 = 5000;
+const WIFI_CONNECT_ATTEMPTS: u32 = 3;
+
+// Non-blocking WiFi connect: sampling starts right away and readings are held (up to the capacity) until
+// the broker connection is up. Held readings wait up to BOOT_BACKLOG_TIME_WAIT_MS for SNTP so they can
+// be published with their original timestamps.
+const WIFI_CONNECT_NON_BLOCKING: bool = false;
+const WIFI_RECONNECT_INTERVAL_MS: u32 = 10000;
+const BOOT_BACKLOG_CAPACITY: usize = 120;
+const BOOT_BACKLOG_TIME_WAIT_MS: u32 = 30000;
+// Held readings older than this are dropped even when there is room, and the order they are published in once
+// connected. Drops by age and by capacity are reported with the next telemetry.
+const BOOT_BACKLOG_MAX_AGE_MS: u32 = 10 * 60 * 1000;
+const BOOT_BACKLOG_FLUSH_ORDER: BacklogFlushOrder = BacklogFlushOrder::OldestFirst;
+// Held readings live on the heap; below this much free heap new readings are dropped (and counted) instead
+const BOOT_BACKLOG_MIN_FREE_HEAP_BYTES: u32 = 32 * 1024;
+// Held readings are published this many per message, so each message fits the MQTT buffer when a reading with
+// its timestamp serializes to at most TELEMETRY_READING_MAX_BYTES
+const BOOT_BACKLOG_FLUSH_CHUNK: usize = 12;
+const TELEMETRY_READING_MAX_BYTES: usize = 512;
+
+// Connectivity check: ICMP ping to the target (None pings the WiFi gateway). After this many failed checks in a
+// row WiFi and MQTT are reconnected instead of letting publishes fail silently.
+const CONNECTIVITY_CHECK_INTERVAL_MS: u32 = 60000;
+const CONNECTIVITY_CHECK_TARGET: Option<Ipv4Addr> = None;
+const CONNECTIVITY_CHECK_PINGS: u32 = 2;
+const CONNECTIVITY_CHECK_TIMEOUT_MS: u64 = 1000;
+const CONNECTIVITY_FAILURES_BEFORE_RECONNECT: u32 = 2;
+
+// Self-healing after consecutive failed telemetry publishes: reconnect MQTT, then WiFi, and reboot as a last
+// resort. Each step fires once per failure streak; a threshold of 0 skips that step.
+const PUBLISH_FAILURES_BEFORE_MQTT_RECONNECT: u32 = 5;
+const PUBLISH_FAILURES_BEFORE_WIFI_RECONNECT: u32 = 10;
+const PUBLISH_FAILURES_BEFORE_REBOOT: u32 = 20;
+
+// MQTT broker. setBroker replaces DEFAULT_CONFIG's settings at runtime: the new settings are stored in NVS and
+// applied by a reboot, then kept on trial until a session comes up. If none does within BROKER_TRIAL_TIMEOUT_MS
+// the previous settings are restored and the device reboots once more.
+const BROKER_NVS_NAMESPACE: &str = "broker";
+const BROKER_TRIAL_TIMEOUT_MS: u32 = 120000;
+const BROKER_FIELD_MAX_BYTES: usize = 256;
+
+// MQTT client id: "<prefix><station MAC>" unless DEFAULT_CONFIG carries an explicit id
+const MQTT_CLIENT_ID_PREFIX: &str = "weather-station-";
+
+// Static device description (firmware, chip, MAC, partition layout), published with the retain flag after every
+// broker connect and again once an OTA has installed a new version. On ThingsBoard it lands as a client
+// attribute, which the server keeps for late subscribers the same way a broker keeps a retained message.
+const DEVICE_INFO_TOPIC: &str = "v1/devices/me/attributes";
+
+// MQTT client receive and send buffer size; topic and data lengths in incoming events are checked against it
+const MQTT_BUFFER_SIZE: i32 = 8192;
+
+// MQTT connection at boot: attempts before rebooting and exponential backoff between them
+const MQTT_CONNECT_ATTEMPTS: u32 = 5;
+const MQTT_CONNECT_BACKOFF_MS: u32 = 2000;
+const MQTT_CONNECT_BACKOFF_MAX_MS: u32 = 60000;
+
+// After a broker reconnect, subscriptions are restored and, once the broker has acknowledged them all,
+// firmware info is requested right away instead of waiting for the next poll (skipped during an update)
+const MQTT_REFRESH_FIRMWARE_INFO_ON_RECONNECT: bool = true;
+
+// Forced reconnects (connectivity loss, publish escalation) stop and restart the MQTT client instead of asking
+// its auto-reconnect to retry, which recovers from failures a retry keeps hitting (stale TLS session, changed auth)
+const MQTT_RESTART_CLIENT_ON_RECONNECT: bool = false;
+
+// A SUBSCRIBE can be accepted by the client yet never acknowledged. Those unacknowledged after the timeout are
+// sent again, up to SUBSCRIBE_ACK_RETRIES times, then given up on and reported as subscription_error telemetry.
+const SUBSCRIBE_ACK_TIMEOUT_MS: u32 = 5000;
+const SUBSCRIBE_ACK_RETRIES: u32 = 3;
+
+// I2C diagnostics
+const I2C_SCAN_FIRST_ADDR: u8 = 0x03;
+const I2C_SCAN_LAST_ADDR: u8 = 0x77;
+const BME280_ADDRESSES: [u8; 2] = [0x76, 0x77];
+
+// I2C bus recovery: after this many consecutive BME280 read failures SCL is clocked by hand to release
+// a slave holding SDA low, then the I2C driver is reinstalled on DEFAULT_CONFIG's pins
+const I2C_RECOVERY_FAILURE_THRESHOLD: u32 = 3;
+const I2C_RECOVERY_CLOCK_PULSES: u32 = 9;
+const I2C_RECOVERY_HALF_PERIOD_US: u32 = 5;
+
+// CO2 ADC, on the channel set in DEFAULT_CONFIG. Attenuation sets the full-scale input voltage (ESP32-S3: 0 dB
+// ~950 mV, 2.5 dB ~1250 mV, 6 dB ~1750 mV, 11/12 dB ~3100 mV); the S3 ADC only samples at 12 bits.
+const CO2_ADC_ATTENUATION: adc_atten_t = adc_atten_t_ADC_ATTEN_DB_11;
+const CO2_ADC_BITWIDTH: adc_bitwidth_t = adc_bitwidth_t_ADC_BITWIDTH_DEFAULT;
+// Reads thrown away right after the channel is configured, while the sample-and-hold settles
+const CO2_ADC_DISCARD_SAMPLES: u32 = 2;
+
+// CO2 calibration in sensor output millivolts: the output falls linearly from CO2_CAL_ZERO_PPM_MV at 0 ppm
+// down to 0 mV at CO2_CAL_MAX_PPM
+const CO2_CAL_ZERO_PPM_MV: f32 = 2650.0;
+const CO2_CAL_MAX_PPM: f32 = 1200.0;
+
+// CO2 temperature/humidity compensation using the BME280 reading taken in the same pass. The MQ-135 sensing
+// resistance drifts with ambient conditions; the quadratic model gives the resistance ratio against the
+// conditions the calibration above was taken at:
+//   factor = a·t² − b·t + c − (h − reference_humidity)·d      (t in °C, h in %RH)
+// The uncompensated estimate is divided by the factor and clamped to the calibrated range. The preset
+// options::CO2_COMPENSATION_MQ135 uses the coefficients fitted to the datasheet sensitivity curves (reference
+// 20 °C / 33 %RH, where the factor is ~1).
+const CO2_COMPENSATION: Co2Compensation = Co2Compensation::None;
+// Factors below this are treated as the fit leaving its valid range and are clamped
+const CO2_COMPENSATION_MIN_FACTOR: f32 = 0.1;
+
+// CO2 sensor fault detection: raw ADC counts within this margin of either rail for this many consecutive
+// samples flag a fault
+const CO2_ADC_RAIL_MARGIN: i32 = 10;
+const CO2_FAULT_CONSECUTIVE_SAMPLES: u32 = 3;
+// After this many consecutive ADC read errors the oneshot unit is deleted and set up again. If that fails too,
+// CO2 is reported as faulted until a read succeeds; recovery is retried after as many further errors.
+const CO2_ADC_RECOVERY_FAILURES: u32 = 5;
+
+// CO2 smoothing: EMA weight of each new sample (1.0 passes readings through unsmoothed).
+// The filter re-seeds from the first good sample after a sensor fault clears.
+const CO2_EMA_ALPHA: f32 = 1.0;
+
+// Internal die temperature sensor measurement range in °C; the driver picks the most accurate band covering it
+const CHIP_TEMP_RANGE_MIN_C: i32 = -10;
+const CHIP_TEMP_RANGE_MAX_C: i32 = 80;
+
+// How co2_ppm is reported when the ADC read fails: null, left out of the payload, or CO2_ERROR_SENTINEL_PPM. With
+// the sentinel every reading also carries co2_valid, false for the sentinel values.
+const CO2_READ_ERROR_REPORT: Co2ReadErrorReport = Co2ReadErrorReport::Null;
+const CO2_ERROR_SENTINEL_PPM: f32 = -1.0;
+
+// Add the raw ADC count behind each CO2 reading to telemetry as co2_adc_raw, for building calibration curves
+const CO2_REPORT_ADC_RAW: bool = false;
+
+// CO2 sensor heater warmup after power-on; readings are reported as null until it elapses
+const CO2_WARMUP_MS: u32 = 120000;
+
+// Additional I2C gas sensors behind a TCA9548A multiplexer, as (mux channel, sensor type) entries.
+// Leave empty to run only the analog CO2 sensor.
+const GAS_SENSORS: &[(u8, GasSensorType)] = &[];
+const I2C_MUX_ADDRESS: u8 = 0x70;
+const I2C_MUX_SWITCH_DELAY_MS: u32 = 5;
+const GAS_SENSOR_I2C_PORT: i2c_port_t = 0;
+const GAS_SENSOR_I2C_TIMEOUT_MS: u32 = 50;
+
+// Aggregate health reported as "health" with every reading. Free heap below the first floor degrades it,
+// below the second it is critical; see SubsystemStatus::health for the full rules.
+const HEALTH_HEAP_DEGRADED_BYTES: u32 = 48 * 1024;
+const HEALTH_HEAP_CRITICAL_BYTES: u32 = 16 * 1024;
+// Publish a sensor_error event (failing sensor and error detail) whenever a sensor read fails, next to the log line
+const SENSOR_ERROR_TELEMETRY: bool = true;
+// How long a startup hardware fault waits for the broker session before giving up on reporting it
+const STARTUP_FAILURE_REPORT_WAIT_MS: u32 = 10000;
+
+// Decimal places kept for each telemetry value
+const TEMPERATURE_DECIMALS: u32 = 2;
+const HUMIDITY_DECIMALS: u32 = 2;
+const PRESSURE_DECIMALS: u32 = 1;
+const CO2_DECIMALS: u32 = 0;
+
+// SNTP: forced re-sync cadence, and how long after the last sync timestamps are flagged unreliable
+const SNTP_RESYNC_INTERVAL_MS: i64 = 24 * 60 * 60 * 1000;
+const SNTP_STALE_AFTER_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+// Until SNTP has synced once, telemetry carries a monotonic uptime_ms with time_source "uptime" instead of a
+// wall-clock ts, so readings can still be ordered; time_source switches to "ntp" after the first sync
+const SNTP_UPTIME_FALLBACK: bool = true;
+
+// Sensor acquisition normally runs in the main loop between publishes, so a slow publish delays the next reading.
+// With the sensor task enabled, readings are taken on their own FreeRTOS task at the sampling interval and queued
+// for the main loop, which only publishes them. The queue drops its oldest reading once it holds the capacity.
+const SENSOR_TASK_ENABLED: bool = false;
+const SENSOR_TASK_STACK_BYTES: u32 = 8192;
+const SENSOR_TASK_PRIORITY: u32 = 5;
+const SENSOR_TASK_QUEUE_CAPACITY: usize = 16;
+
+// Sensor sampling during OTA. A download runs the loop every 100 ms to service chunks; by default
+// readings are then tied to the OTA telemetry throttle, set this to keep the normal cadence instead.
+const SENSOR_TELEMETRY_DURING_OTA: bool = false;
+// Skip sensor reads entirely while firmware chunks are being written, so flash erase/write bursts don't
+// collide with I2C and ADC2 timing; sampling resumes once the download leaves DOWNLOADING.
+const SENSOR_PAUSE_DURING_FLASH_WRITES: bool = false;
+
+// After a failed update the same firmware is not retried for OTA_RETRY_COOLDOWN_MS. Meanwhile every poll that
+// still advertises it doubles the poll interval, up to the maximum; a different version ends the cooldown early.
+const OTA_RETRY_COOLDOWN_MS: u32 = 10 * 60 * 1000;
+const FIRMWARE_INFO_POLL_BACKOFF_MAX_MS: u32 = 5 * 60 * 1000;
+
+// boostTelemetry RPC: limits on the temporary sampling interval and on how long a boost may last.
+// A boost only shortens the normal loop; sampling during an OTA download keeps its own timing.
+const TELEMETRY_BOOST_MIN_INTERVAL_MS: u32 = 200;
+const TELEMETRY_BOOST_MAX_SECONDS: u32 = 600;
+
+// Telemetry batching (ThingsBoard multi-value format); a batch size of 1 publishes every reading immediately
+const TELEMETRY_BATCH_SIZE: usize = 1;
+const TELEMETRY_BATCH_FLUSH_INTERVAL_MS: u32 = 60000;
+
+// Publish-on-change: skip readings that stay within these deltas of the last published value,
+// but never stay silent for longer than PUBLISH_MAX_SILENCE_MS
+const PUBLISH_ON_CHANGE_ENABLED: bool = false;
+const PUBLISH_MAX_SILENCE_MS: u32 = 300000;
+const TEMPERATURE_CHANGE_DELTA: f64 = 0.2;
+const HUMIDITY_CHANGE_DELTA: f64 = 1.0;
+const PRESSURE_CHANGE_DELTA: f64 = 0.5;
+const CO2_CHANGE_DELTA: f64 = 25.0;
+const DEFAULT_CHANGE_DELTA: f64 = 1.0;
+
+// Threshold alarms on the readings, each with a warning and a critical level. A level only fires after
+// ALARM_DEBOUNCE_SAMPLES consecutive readings beyond its threshold, and steps back down once the value is past the
+// threshold by the rule's hysteresis. Warnings and their clears go out with the telemetry; anything involving
+// the critical level goes to its own topic at a higher QoS.
+const ALARM_RULES: &[AlarmRule] = &[
+    AlarmRule { metric: AlarmMetric::Temperature, direction: AlarmDirection::Above, warning: 40.0, critical: 50.0, hysteresis: 1.0 },
+    AlarmRule { metric: AlarmMetric::Co2Ppm, direction: AlarmDirection::Above, warning: 1000.0, critical: 2000.0, hysteresis: 50.0 },
+];
+const ALARM_DEBOUNCE_SAMPLES: u32 = 3;
+const ALARM_WARNING_TOPIC: &str = "v1/devices/me/telemetry";
+const ALARM_WARNING_QOS: i32 = 0;
+const ALARM_CRITICAL_TOPIC: &str = "v1/devices/me/attributes";
+const ALARM_CRITICAL_QOS: i32 = 1;
+
+// Sensor telemetry encoding; OTA control messages always stay JSON
+const TELEMETRY_ENCODING: TelemetryEncoding = TelemetryEncoding::Json;
+const TELEMETRY_CBOR_TOPIC: &str = "v1/devices/me/telemetry/cbor";
+
+// Additional telemetry destinations, each fed every reading alongside ThingsBoard. `fields` maps telemetry keys
+// to the names the sink expects and drops everything else; leave it empty to forward all keys unchanged. Readings
+// carry "ts" (ms since epoch) once SNTP has synced. A failed publish to one sink is logged and the rest still go out.
+const TELEMETRY_SINKS: &[TelemetrySink] = &[];
+
+struct TelemetrySink {
+    topic: &'static str,
+    format: SinkFormat,
+    fields: &'static [(&'static str, &'static str)],
+    qos: i32,
+}
+
+// ThingsBoard gateway mode. The station also acts as a gateway and reports each sub-sensor as its own device:
+// every reading goes out on GATEWAY_TELEMETRY_TOPIC as {"<device>": [{"ts": ..., "values": {...}}], ...}. `fields`
+// maps telemetry keys to the names used on that device, as for TELEMETRY_SINKS. ThingsBoard creates unknown devices
+// on first telemetry. The station's own telemetry is still published, so single-device dashboards keep working.
+// The device profile needs "Is gateway" enabled for the gateway topics to be accepted.
+const GATEWAY_MODE_ENABLED: bool = false;
+const GATEWAY_TELEMETRY_TOPIC: &str = "v1/gateway/telemetry";
+const GATEWAY_DEVICES: &[GatewayDevice] = &[
+    GatewayDevice {
+        name: "Weather Station BME280",
+        fields: &[("temperature", "temperature"), ("humidity", "humidity"), ("pressure", "pressure")],
+    },
+    GatewayDevice {
+        name: "Weather Station MQ-135",
+        fields: &[("co2_ppm", "co2_ppm"), ("co2_sensor_fault", "sensor_fault"), ("co2_warming_up", "warming_up")],
+    },
+];
+
+struct GatewayDevice {
+    name: &'static str,
+    fields: &'static [(&'static str, &'static str)],
+}
+
+// Gzip for large JSON telemetry (typically batches). Payloads of at least TELEMETRY_GZIP_MIN_BYTES are
+// compressed into a standard gzip member (RFC 1952) and published to TELEMETRY_GZIP_TOPIC; smaller ones stay
+// plain JSON on the telemetry topic. The receiver gunzips messages from the gzip topic and handles the result
+// exactly like a message on the telemetry topic.
+const TELEMETRY_GZIP_ENABLED: bool = false;
+const TELEMETRY_GZIP_MIN_BYTES: usize = 1024;
+const TELEMETRY_GZIP_TOPIC: &str = "v1/devices/me/telemetry/gzip";
+const TELEMETRY_GZIP_LEVEL: u8 = 6;
+
+// BME280 measurement mode. Forced takes one conversion per sampling cycle and lets the sensor sleep in
+// between, which suits the low sampling rate. Normal keeps the sensor converting continuously with
+// BME280_NORMAL_STANDBY_MS between conversions, and each sample reads the latest result without triggering one.
+const BME280_MODE: Bme280Mode = Bme280Mode::Forced;
+// Normal-mode standby time: 0.5, 10, 20, 62.5, 125, 250, 500 or 1000 ms
+const BME280_NORMAL_STANDBY_MS: f32 = 1000.0;
+
+// BME280 sampling: oversampling factors (1, 2, 4, 8, 16) and IIR filter coefficient (0 = off, 2, 4, 8, 16).
+// Defaults follow Bosch's recommended weather monitoring profile.
+const BME280_DEFAULT_SETTINGS: Bme280Settings = Bme280Settings {
+    temperature_oversampling: 1,
+    pressure_oversampling: 1,
+    humidity_oversampling: 1,
+    iir_filter: 0,
+};
+
+// Persistent OTA event log on the spiffs partition, keeping the most recent entries
+const OTA_EVENT_LOG_ENABLED: bool = true;
+const OTA_EVENT_LOG_CAPACITY: usize = 32;
+const SPIFFS_BASE_PATH: &CStr = c"/spiffs";
+const SPIFFS_PARTITION_LABEL: &CStr = c"spiffs";
+const OTA_EVENT_LOG_PATH: &CStr = c"/spiffs/ota_events.log";
+
+// Local HTTP status page (feature "http-status")
+#[cfg(feature = "http-status")]
+const STATUS_SERVER_PORT: u16 = 80;
+
+// Secondary telemetry endpoint (feature "http-mirror"): every telemetry payload is also POSTed here as JSON
+#[cfg(feature = "http-mirror")]
+const HTTP_MIRROR_URL: &CStr = c"http://192.168.1.10:8080/telemetry";
+#[cfg(feature = "http-mirror")]
+const HTTP_MIRROR_TIMEOUT_MS: i32 = 3000;
+
+// Build metadata (injected by build.rs)
+const FW_BUILD_TIMESTAMP: &str = env!("FW_BUILD_TIMESTAMP");
+const FW_GIT_HASH: &str = env!("FW_GIT_HASH");
+
+// Partition handles may be distinct pointers to the same table entry, so compare what they describe
+unsafe fn same_partition(a: *const esp_partition_t, b: *const esp_partition_t) -> bool {
+    (*a).address == (*b).address && (*a).label == (*b).label
+}
+
+// Only an OTA app slot may be erased for an update; anything else means the partition table is not what we expect
+unsafe fn check_update_partition(partition: *const esp_partition_t) -> Result<()> {
+    let label = CStr::from_ptr((*partition).label.as_ptr()).to_str().unwrap_or("unknown");
+    if (*partition).type_ != esp_partition_type_t_ESP_PARTITION_TYPE_APP {
+        return Err(anyhow!("Selected partition {} at 0x{:x} is not an app partition (type {})",
+            label, (*partition).address, (*partition).type_));
+    }
+    let ota_subtypes = esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MIN..esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MAX;
+    if !ota_subtypes.contains(&(*partition).subtype) {
+        return Err(anyhow!("Selected partition {} at 0x{:x} is not an OTA app slot (subtype 0x{:x})",
+            label, (*partition).address, (*partition).subtype));
+    }
+    Ok(())
+}
 
 #[inline(always)]
 fn ms_to_ticks(ms: u32) -> u32 {
     (ms as u64 * configTICK_RATE_HZ as u64 / 1000) as u32
 }
 
+// Ticks from `since` to `now`, correct across a tick counter wrap. None when `since` is ahead of `now` by
+// more than half the counter range, which no real elapsed time can produce.
+fn ticks_elapsed(now: u32, since: u32) -> Option<u32> {
+    let elapsed = now.wrapping_sub(since);
+    if elapsed > u32::MAX / 2 {
+        None
+    } else {
+        Some(elapsed)
+    }
+}
+
+#[inline(always)]
+fn ticks_to_ms(ticks: u32) -> u32 {
+    (ticks as u64 * 1000 / configTICK_RATE_HZ as u64) as u32
+}
+
+// Rounds half away from zero; the result stays f32 so serialization prints the shortest exact form
+fn round_to(value: f32, decimals: u32) -> f32 {
+    if !value.is_finite() {
+        return value;
+    }
+    let factor = (0..decimals).fold(1.0f64, |factor, _| factor * 10.0);
+    let scaled = value as f64 * factor;
+    let rounded = if scaled >= 0.0 { (scaled + 0.5) as i64 } else { (scaled - 0.5) as i64 };
+    (rounded as f64 / factor) as f32
+}
+
+// A value behind its own CriticalSection, so tasks sharing it only ever need a shared reference
+struct Guarded<T> {
+    lock: CriticalSection,
+    value: UnsafeCell<T>,
+}
+
+// Every access goes through `with`, which holds the lock for as long as the mutable borrow lives
+unsafe impl<T: Send> Sync for Guarded<T> {}
+
+impl<T> Guarded<T> {
+    fn new(value: T) -> Self {
+        Self { lock: CriticalSection::new(), value: UnsafeCell::new(value) }
+    }
+
+    // Must not be nested on the same value: the lock is re-entrant within a task
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _guard = self.lock.enter();
+        f(unsafe { &mut *self.value.get() })
+    }
+}
+
+fn co2_warming_up() -> bool {
+    let uptime_ms = unsafe { esp_timer_get_time() } / 1000;
+    uptime_ms < CO2_WARMUP_MS as i64
+}
+
+#[allow(non_upper_case_globals)] // matches on bindgen constant names
+fn adc_full_scale_mv(atten: adc_atten_t) -> Option<u32> {
+    match atten {
+        adc_atten_t_ADC_ATTEN_DB_0 => Some(950),
+        adc_atten_t_ADC_ATTEN_DB_2_5 => Some(1250),
+        adc_atten_t_ADC_ATTEN_DB_6 => Some(1750),
+        adc_atten_t_ADC_ATTEN_DB_11 => Some(3100),
+        _ => None,
+    }
+}
+
+#[allow(non_upper_case_globals)] // matches on bindgen constant names
+fn adc_resolution_bits(bitwidth: adc_bitwidth_t) -> Option<u32> {
+    match bitwidth {
+        adc_bitwidth_t_ADC_BITWIDTH_DEFAULT | adc_bitwidth_t_ADC_BITWIDTH_12 => Some(12),
+        _ => None,
+    }
+}
+
+fn adc_max_count() -> i32 {
+    (1 << adc_resolution_bits(CO2_ADC_BITWIDTH).unwrap_or(12)) - 1
+}
+
+fn validate_adc_config() -> Result<()> {
+    let full_scale_mv = adc_full_scale_mv(CO2_ADC_ATTENUATION)
+        .ok_or_else(|| anyhow!("Unsupported ADC attenuation {}", CO2_ADC_ATTENUATION))?;
+    let bits = adc_resolution_bits(CO2_ADC_BITWIDTH)
+        .ok_or_else(|| anyhow!("Unsupported ADC bitwidth {}, the ESP32-S3 ADC is 12-bit only", CO2_ADC_BITWIDTH))?;
+    if CO2_CAL_ZERO_PPM_MV > full_scale_mv as f32 {
+        error!("CO2 calibration tops out at {} mV, above the {} mV full scale; readings near 0 ppm will clip",
+            CO2_CAL_ZERO_PPM_MV, full_scale_mv);
+    }
+    info!("CO2 ADC: {} bit, full scale {} mV", bits, full_scale_mv);
+    Ok(())
+}
+
 fn adc_to_ppm(adc_raw: i32) -> f32 {
-    let adc_min = 0.0;
-    let adc_max = 3500.0;
+    let full_scale_mv = adc_full_scale_mv(CO2_ADC_ATTENUATION).unwrap_or(3100) as f32;
+    let mv = adc_raw as f32 * full_scale_mv / adc_max_count() as f32;
     let ppm_min = 0.0;
-    let ppm_max = 1200.0;
-    let adc_f = adc_raw as f32;
-    let ppm = (adc_max - adc_f) / (adc_max - adc_min) * (ppm_max - ppm_min) + ppm_min;
+    let ppm_max = CO2_CAL_MAX_PPM;
+    let ppm = (CO2_CAL_ZERO_PPM_MV - mv) / CO2_CAL_ZERO_PPM_MV * (ppm_max - ppm_min) + ppm_min;
     if ppm < ppm_min {
         ppm_min
     } else if ppm > ppm_max {
@@ -56,678 +644,4871 @@ fn adc_to_ppm(adc_raw: i32) -> f32 {
     }
 }
 
-#[derive(PartialEq)]
-enum OtaState {
-    Idle,
-    Downloading,
-    Downloaded,
-    Verifying,
-    Updating,
-    Updated,
-    Failed(String),
+// Ratio of the sensor response at the given conditions to the response at calibration conditions
+fn co2_compensation_factor(temperature: f32, humidity: f32) -> f32 {
+    match CO2_COMPENSATION {
+        Co2Compensation::None => 1.0,
+        Co2Compensation::Quadratic { a, b, c, d, reference_humidity } => {
+            let factor = a * temperature * temperature - b * temperature + c - (humidity - reference_humidity) * d;
+            factor.max(CO2_COMPENSATION_MIN_FACTOR)
+        }
+    }
 }
 
-struct OtaManager {
-    current_fw_title: String,
-    current_fw_version: String,
-    fw_title: Option<String>,
-    fw_version: Option<String>,
-    fw_size: Option<u32>,
-    fw_checksum: Option<String>,
-    fw_checksum_algorithm: Option<String>,
-    ota_state: OtaState,
-    request_id: u32,
-    firmware_request_id: u32,
-    current_chunk: u32,
-    ota_handle: esp_ota_handle_t,
-    ota_partition: *const esp_partition_t,
-    received_size: usize,
-    sha256_hasher: Sha256,
-    partial_firmware_data: Vec<u8>,
-    chunk_buffer: Vec<(u32, Vec<u8>)>,
-    chunk_size: usize,
-    last_chunk_received: u32,
-    telemetry_counter: u32,
+fn compensate_co2_ppm(ppm: f32, temperature: f32, humidity: f32) -> f32 {
+    (ppm / co2_compensation_factor(temperature, humidity)).clamp(0.0, CO2_CAL_MAX_PPM)
 }
 
-impl OtaManager {
-    fn new() -> Self {
-        unsafe {
-            let otadata_partition = esp_partition_find_first(
-                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
-                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_OTA,
-                core::ptr::null()
-            );
-            if !otadata_partition.is_null() {
-                info!("Found otadata partition at {:p}", otadata_partition);
-            } else {
-                error!("No otadata partition found");
-            }
+// Oneshot handle for the CO2 sensor channel; the ADC unit is released when this is dropped
+struct Co2Adc {
+    handle: adc_oneshot_unit_handle_t,
+}
 
-            let mut ota_partitions_found = 0;
-            for subtype in &[esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_0, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_1] {
-                let mut iterator = esp_partition_find(
-                    esp_partition_type_t_ESP_PARTITION_TYPE_APP,
-                    *subtype,
-                    core::ptr::null()
-                );
-                while !iterator.is_null() {
-                    let partition = esp_partition_get(iterator);
-                    let label = core::ffi::CStr::from_ptr((*partition).label.as_ptr()).to_str().unwrap_or("unknown");
-                    info!("Found OTA partition: {}, subtype: {:?}, address: 0x{:x}, size: 0x{:x}",
-                        label, *subtype, (*partition).address, (*partition).size);
-                    ota_partitions_found += 1;
-                    iterator = esp_partition_next(iterator);
-                }
-                esp_partition_iterator_release(iterator);
-            }
-            if ota_partitions_found < 2 {
-                error!("Insufficient OTA partitions found: {}. Need at least 2 for OTA.", ota_partitions_found);
-            } else {
-                info!("Found {} OTA partitions, sufficient for OTA", ota_partitions_found);
-            }
+impl Co2Adc {
+    fn new() -> Result<Self> {
+        let init_cfg = adc_oneshot_unit_init_cfg_t {
+            unit_id: adc_unit_t_ADC_UNIT_2,
+            clk_src: soc_periph_adc_rtc_clk_src_t_ADC_RTC_CLK_SRC_DEFAULT,
+            ..Default::default()
+        };
+        let mut handle: adc_oneshot_unit_handle_t = core::ptr::null_mut();
+        let res = unsafe { adc_oneshot_new_unit(&init_cfg, &mut handle) };
+        if res != ESP_OK {
+            return Err(anyhow!("Failed to init ADC unit: {}", res));
+        }
+        // From here on an early return releases the unit through Drop
+        let adc = Self { handle };
 
-            let running_partition = esp_ota_get_running_partition();
-            if !running_partition.is_null() {
-                let label = core::ffi::CStr::from_ptr((*running_partition).label.as_ptr()).to_str().unwrap_or("unknown");
-                info!("Current running partition: {}, address: 0x{:x}, size: 0x{:x}",
-                    label, (*running_partition).address, (*running_partition).size);
-            } else {
-                error!("No running partition detected");
+        let chan_cfg = adc_oneshot_chan_cfg_t {
+            atten: CO2_ADC_ATTENUATION,
+            bitwidth: CO2_ADC_BITWIDTH,
+        };
+        let res = unsafe { adc_oneshot_config_channel(adc.handle, DEFAULT_CONFIG.co2_adc_channel, &chan_cfg) };
+        if res != ESP_OK {
+            return Err(anyhow!("Failed to config ADC channel: {}", res));
+        }
+        for _ in 0..CO2_ADC_DISCARD_SAMPLES {
+            if let Err(e) = adc.read() {
+                error!("Discarded settling read failed: {:?}", e);
             }
         }
+        Ok(adc)
+    }
 
-        Self {
-            current_fw_title: "Weather Station".to_string(),
-            current_fw_version: "V1.0".to_string(),
-            fw_title: None,
-            fw_version: None,
-            fw_size: None,
-            fw_checksum: None,
-            fw_checksum_algorithm: None,
-            ota_state: OtaState::Idle,
-            request_id: 0,
-            firmware_request_id: 0,
-            current_chunk: 0,
-            ota_handle: 0,
-            ota_partition: core::ptr::null(),
-            received_size: 0,
-            sha256_hasher: Sha256::new(),
-            partial_firmware_data: Vec::new(),
-            chunk_buffer: Vec::with_capacity(10),
-            chunk_size: 4096,
-            last_chunk_received: 0,
-            telemetry_counter: 0,
+    fn read(&self) -> Result<i32> {
+        if self.handle.is_null() {
+            return Err(anyhow!("ADC read error: unit not initialised"));
+        }
+        let mut value: i32 = 0;
+        let res = unsafe { adc_oneshot_read(self.handle, DEFAULT_CONFIG.co2_adc_channel, &mut value) };
+        if res != ESP_OK {
+            return Err(anyhow!("ADC read error: {}", res));
         }
+        Ok(value)
     }
 
-    fn handle_shared_attributes(&mut self, attributes: &str, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        let attrs: Value = serde_json::from_str(attributes)?;
-        info!("Raw attributes received: {}", attributes);
+    // Deletes the oneshot unit and sets it up again; on failure the handle stays null and every read fails
+    fn reinit(&mut self) -> Result<()> {
+        if !self.handle.is_null() {
+            unsafe {
+                adc_oneshot_del_unit(self.handle);
+            }
+        }
+        self.handle = core::ptr::null_mut();
+        let mut fresh = Self::new()?;
+        self.handle = core::mem::replace(&mut fresh.handle, core::ptr::null_mut());
+        Ok(())
+    }
+}
 
-        let shared_attrs = attrs.get("shared").ok_or_else(|| anyhow!("Missing 'shared' object in attributes"))?;
+// Internal die temperature sensor; readings are None on chips where it could not be brought up
+struct ChipTemperature {
+    handle: Option<temperature_sensor_handle_t>,
+}
 
-        if let Some(fw_title) = shared_attrs.get(FW_TITLE_ATTR).and_then(|v| v.as_str()) {
-            self.fw_title = Some(fw_title.trim().to_string());
-            info!("Received fw_title: '{}'", fw_title);
-        }
-        if let Some(fw_version) = shared_attrs.get(FW_VERSION_ATTR).and_then(|v| v.as_str()) {
-            self.fw_version = Some(fw_version.trim().to_string());
-            info!("Received fw_version: '{}'", fw_version);
-        }
-        if let Some(fw_size) = shared_attrs.get(FW_SIZE_ATTR).and_then(|v| v.as_u64()) {
-            self.fw_size = Some(fw_size as u32);
-            info!("Received fw_size: {}", fw_size);
+impl ChipTemperature {
+    fn open() -> Self {
+        let cfg = temperature_sensor_config_t {
+            range_min: CHIP_TEMP_RANGE_MIN_C,
+            range_max: CHIP_TEMP_RANGE_MAX_C,
+            ..Default::default()
+        };
+        let mut handle: temperature_sensor_handle_t = core::ptr::null_mut();
+        let res = unsafe { temperature_sensor_install(&cfg, &mut handle) };
+        if res != ESP_OK {
+            info!("Chip temperature sensor unavailable: {}", res);
+            return Self { handle: None };
         }
-        if let Some(fw_checksum) = shared_attrs.get(FW_CHECKSUM_ATTR).and_then(|v| v.as_str()) {
-            self.fw_checksum = Some(fw_checksum.trim().to_string());
-            info!("Received fw_checksum: '{}'", fw_checksum);
+        let res = unsafe { temperature_sensor_enable(handle) };
+        if res != ESP_OK {
+            info!("Failed to enable chip temperature sensor: {}", res);
+            unsafe { temperature_sensor_uninstall(handle); }
+            return Self { handle: None };
         }
-        if let Some(fw_checksum_alg) = shared_attrs.get(FW_CHECKSUM_ALG_ATTR).and_then(|v| v.as_str()) {
-            self.fw_checksum_algorithm = Some(fw_checksum_alg.trim().to_string());
-            info!("Received fw_checksum_algorithm: '{}'", fw_checksum_alg);
+        Self { handle: Some(handle) }
+    }
+
+    fn read(&self) -> Option<f32> {
+        let handle = self.handle?;
+        let mut celsius: f32 = 0.0;
+        let res = unsafe { temperature_sensor_get_celsius(handle, &mut celsius) };
+        if res != ESP_OK {
+            error!("Chip temperature read error: {}", res);
+            return None;
+        }
+        Some(celsius)
+    }
+}
+
+impl Drop for ChipTemperature {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle {
+            unsafe {
+                temperature_sensor_disable(handle);
+                temperature_sensor_uninstall(handle);
+            }
+        }
+    }
+}
+
+impl Drop for Co2Adc {
+    fn drop(&mut self) {
+        if !self.handle.is_null() {
+            unsafe {
+                adc_oneshot_del_unit(self.handle);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Bme280Settings {
+    temperature_oversampling: u8,
+    pressure_oversampling: u8,
+    humidity_oversampling: u8,
+    iir_filter: u8,
+}
+
+impl Bme280Settings {
+    fn to_configuration(self) -> Result<Bme280Configuration> {
+        Ok(Bme280Configuration::default()
+            .with_temperature_oversampling(Self::oversampling(self.temperature_oversampling)?)
+            .with_pressure_oversampling(Self::oversampling(self.pressure_oversampling)?)
+            .with_humidity_oversampling(Self::oversampling(self.humidity_oversampling)?)
+            .with_iir_filter(Self::iir_filter(self.iir_filter)?))
+    }
+
+    fn oversampling(factor: u8) -> Result<Oversampling> {
+        match factor {
+            1 => Ok(Oversampling::Oversampling1X),
+            2 => Ok(Oversampling::Oversampling2X),
+            4 => Ok(Oversampling::Oversampling4X),
+            8 => Ok(Oversampling::Oversampling8X),
+            16 => Ok(Oversampling::Oversampling16X),
+            _ => Err(anyhow!("Unsupported oversampling factor: {}", factor)),
+        }
+    }
+
+    // Normal mode is started by hand on the sleeping sensor that init_with_config leaves behind: the bme280 driver
+    // has no normal mode. config is only writable in sleep mode, and ctrl_meas goes last since it latches ctrl_hum
+    // and starts the conversions (datasheet section 5.4).
+    fn start_normal_mode(self) -> Result<()> {
+        let standby = bme280_standby_code(BME280_NORMAL_STANDBY_MS)
+            .ok_or_else(|| anyhow!("Unsupported BME280 standby time: {} ms", BME280_NORMAL_STANDBY_MS))?;
+        let address = BME280_ADDRESSES[0];
+        i2c_write_raw(address, &[0xf5, standby << 5 | Self::filter_code(self.iir_filter)? << 2])?;
+        i2c_write_raw(address, &[0xf2, Self::oversampling_code(self.humidity_oversampling)?])?;
+        let ctrl_meas = Self::oversampling_code(self.temperature_oversampling)? << 5
+            | Self::oversampling_code(self.pressure_oversampling)? << 2
+            | 0b11;
+        i2c_write_raw(address, &[0xf4, ctrl_meas])
+    }
+
+    fn oversampling_code(factor: u8) -> Result<u8> {
+        match factor {
+            1 => Ok(1),
+            2 => Ok(2),
+            4 => Ok(3),
+            8 => Ok(4),
+            16 => Ok(5),
+            _ => Err(anyhow!("Unsupported oversampling factor: {}", factor)),
+        }
+    }
+
+    fn filter_code(coefficient: u8) -> Result<u8> {
+        match coefficient {
+            0 => Ok(0),
+            2 => Ok(1),
+            4 => Ok(2),
+            8 => Ok(3),
+            16 => Ok(4),
+            _ => Err(anyhow!("Unsupported IIR filter coefficient: {}", coefficient)),
+        }
+    }
+
+    // Worst-case forced conversion time from the datasheet (section 9.1), rounded up to whole milliseconds
+    fn measurement_time_ms(&self) -> u32 {
+        let time_us = 1250
+            + 2300 * self.temperature_oversampling as u32
+            + 2300 * self.pressure_oversampling as u32 + 575
+            + 2300 * self.humidity_oversampling as u32 + 575;
+        time_us.div_ceil(1000)
+    }
+
+    fn iir_filter(coefficient: u8) -> Result<IIRFilter> {
+        match coefficient {
+            0 => Ok(IIRFilter::Off),
+            2 => Ok(IIRFilter::Coefficient2),
+            4 => Ok(IIRFilter::Coefficient4),
+            8 => Ok(IIRFilter::Coefficient8),
+            16 => Ok(IIRFilter::Coefficient16),
+            _ => Err(anyhow!("Unsupported IIR filter coefficient: {}", coefficient)),
+        }
+    }
+
+    fn with_overrides(&self, params: &Value) -> Result<Self> {
+        let field = |name: &str, current: u8| -> Result<u8> {
+            match params.get(name) {
+                None => Ok(current),
+                Some(v) => v.as_u64()
+                    .and_then(|v| u8::try_from(v).ok())
+                    .ok_or_else(|| anyhow!("Invalid value for {}: {}", name, v)),
+            }
+        };
+        let settings = Self {
+            temperature_oversampling: field("temperature_oversampling", self.temperature_oversampling)?,
+            pressure_oversampling: field("pressure_oversampling", self.pressure_oversampling)?,
+            humidity_oversampling: field("humidity_oversampling", self.humidity_oversampling)?,
+            iir_filter: field("iir_filter", self.iir_filter)?,
+        };
+        settings.to_configuration()?;
+        Ok(settings)
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "temperature_oversampling": self.temperature_oversampling,
+            "pressure_oversampling": self.pressure_oversampling,
+            "humidity_oversampling": self.humidity_oversampling,
+            "iir_filter": self.iir_filter,
+            "measurement_time_ms": self.measurement_time_ms()
+        })
+    }
+}
+
+// Most recent sensor values, shared with consumers outside the main loop (e.g. the HTTP status page)
+#[derive(Clone, Copy, Default)]
+struct LatestReadings {
+    temperature: Option<f32>,
+    humidity: Option<f32>,
+    pressure_hpa: Option<f32>,
+    co2_ppm: Option<f32>,
+    reading_count: u32,
+    updated_uptime_ms: i64,
+}
+
+impl LatestReadings {
+    fn update(&mut self, temperature: f32, humidity: f32, pressure: f32, co2_ppm: Option<f32>) {
+        self.temperature = Some(temperature);
+        self.humidity = Some(humidity);
+        self.pressure_hpa = Some(pressure / 100.0);
+        self.co2_ppm = co2_ppm;
+        self.reading_count += 1;
+        self.updated_uptime_ms = unsafe { esp_timer_get_time() } / 1000;
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "temperature": self.temperature,
+            "humidity": self.humidity,
+            "pressure": self.pressure_hpa,
+            "co2_ppm": self.co2_ppm,
+            "reading_count": self.reading_count,
+            "updated_uptime_ms": self.updated_uptime_ms
+        })
+    }
+}
+
+struct Co2FaultDetector {
+    consecutive_rail_samples: u32,
+}
+
+impl Co2FaultDetector {
+    fn new() -> Self {
+        Self { consecutive_rail_samples: 0 }
+    }
+
+    fn update(&mut self, adc_raw: i32) -> bool {
+        if adc_raw <= CO2_ADC_RAIL_MARGIN || adc_raw >= adc_max_count() - CO2_ADC_RAIL_MARGIN {
+            self.consecutive_rail_samples = self.consecutive_rail_samples.saturating_add(1);
+            if self.consecutive_rail_samples == CO2_FAULT_CONSECUTIVE_SAMPLES {
+                error!("CO2 sensor fault: ADC pinned at rail ({}) for {} samples", adc_raw, CO2_FAULT_CONSECUTIVE_SAMPLES);
+            }
+        } else {
+            if self.is_faulted() {
+                info!("CO2 sensor fault cleared, ADC raw: {}", adc_raw);
+            }
+            self.consecutive_rail_samples = 0;
+        }
+        self.is_faulted()
+    }
+
+    fn is_faulted(&self) -> bool {
+        self.consecutive_rail_samples >= CO2_FAULT_CONSECUTIVE_SAMPLES
+    }
+}
+
+// Recreates the ADC unit when reads keep failing, e.g. an ADC2 unit that has wedged
+struct Co2AdcRecovery {
+    consecutive_errors: u32,
+    recoveries: u32,
+    // The last recreate failed; cleared by the next successful read
+    failed: bool,
+}
+
+impl Co2AdcRecovery {
+    fn new() -> Self {
+        Self { consecutive_errors: 0, recoveries: 0, failed: false }
+    }
+
+    fn record(&mut self, co2_adc: &mut Co2Adc, read_ok: bool) {
+        if read_ok {
+            if self.consecutive_errors > 0 || self.failed {
+                info!("CO2 ADC reads recovered after {} errors", self.consecutive_errors);
+            }
+            self.consecutive_errors = 0;
+            self.failed = false;
+            return;
+        }
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        if self.consecutive_errors < CO2_ADC_RECOVERY_FAILURES {
+            return;
+        }
+        error!("CO2 ADC failed {} reads in a row, recreating the ADC unit", self.consecutive_errors);
+        self.consecutive_errors = 0;
+        match co2_adc.reinit() {
+            Ok(()) => {
+                self.recoveries = self.recoveries.saturating_add(1);
+                info!("CO2 ADC recovery #{} done", self.recoveries);
+            }
+            Err(e) => {
+                error!("CO2 ADC recovery failed, reporting the CO2 sensor as faulted: {:?}", e);
+                self.failed = true;
+            }
+        }
+    }
+}
+
+struct EmaFilter {
+    alpha: f32,
+    value: Option<f32>,
+}
+
+impl EmaFilter {
+    fn new(alpha: f32) -> Self {
+        Self { alpha, value: None }
+    }
+
+    fn update(&mut self, sample: f32) -> f32 {
+        let smoothed = match self.value {
+            Some(previous) => previous + self.alpha * (sample - previous),
+            None => sample,
+        };
+        self.value = Some(smoothed);
+        smoothed
+    }
+
+    fn reset(&mut self) {
+        self.value = None;
+    }
+}
+
+struct I2cBusMonitor {
+    consecutive_failures: u32,
+    recoveries: u32,
+    // Recovery event waiting to be published once MQTT is connected
+    pending_event: Option<Value>,
+}
+
+impl I2cBusMonitor {
+    fn new() -> Self {
+        Self { consecutive_failures: 0, recoveries: 0, pending_event: None }
+    }
+
+    fn record_success(&mut self) {
+        if self.consecutive_failures > 0 {
+            info!("I2C bus healthy again after {} failed reads", self.consecutive_failures);
+        }
+        self.consecutive_failures = 0;
+    }
+
+    // Returns true when the failure count has reached the recovery threshold
+    fn record_failure(&mut self) -> bool {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.consecutive_failures >= I2C_RECOVERY_FAILURE_THRESHOLD
+    }
+
+    fn recover(&mut self, bme280: BME280<I2cDriver<'static>>, settings: &Bme280Settings) -> BME280<I2cDriver<'static>> {
+        error!("I2C bus stuck after {} consecutive failures, starting bus recovery", self.consecutive_failures);
+        // Dropping the sensor drops its I2cDriver, which uninstalls the driver and frees the pins
+        drop(bme280);
+        let sda_released = release_i2c_bus();
+        let mut bme280 = match reinstall_i2c_driver() {
+            Ok(i2c) => BME280::new_primary(i2c),
+            Err(e) => {
+                error!("Failed to reinstall I2C driver: {:?}, restarting", e);
+                unsafe { esp_restart(); }
+            }
+        };
+        let reinit = init_bme280(&mut bme280, settings);
+        if let Err(e) = &reinit {
+            error!("BME280 re-init after bus recovery failed: {:?}", e);
+        }
+
+        self.recoveries = self.recoveries.saturating_add(1);
+        info!("I2C bus recovery #{} done (SDA released: {}, BME280 re-init: {})", self.recoveries, sda_released, reinit.is_ok());
+        self.pending_event = Some(json!({
+            "i2c_bus_recovery": true,
+            "i2c_bus_recoveries": self.recoveries,
+            "i2c_failures_before_recovery": self.consecutive_failures,
+            "i2c_sda_released": sda_released,
+            "bme280_reinit_ok": reinit.is_ok(),
+        }));
+        self.consecutive_failures = 0;
+        bme280
+    }
+}
+
+impl GasSensorType {
+    fn name(&self) -> &'static str {
+        match self {
+            GasSensorType::Scd41 => "scd41",
+            GasSensorType::Sgp30 => "sgp30",
+        }
+    }
+
+    fn address(&self) -> u8 {
+        match self {
+            GasSensorType::Scd41 => 0x62,
+            GasSensorType::Sgp30 => 0x58,
+        }
+    }
+}
+
+struct GasSensor {
+    mux_channel: u8,
+    sensor_type: GasSensorType,
+    initialized: bool,
+}
+
+impl GasSensor {
+    // Telemetry keys are prefixed so several sensors of the same type stay distinct
+    fn key_prefix(&self) -> String {
+        format!("{}_ch{}", self.sensor_type.name(), self.mux_channel)
+    }
+
+    fn init(&mut self) -> Result<()> {
+        select_mux_channel(self.mux_channel)?;
+        match self.sensor_type {
+            // start_periodic_measurement, new data every 5 s
+            GasSensorType::Scd41 => sensirion_command(self.sensor_type.address(), 0x21b1)?,
+            // sgp30_iaq_init, baseline settles over the first 15 s
+            GasSensorType::Sgp30 => sensirion_command(self.sensor_type.address(), 0x2003)?,
+        }
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Vec<(&'static str, f32)>> {
+        select_mux_channel(self.mux_channel)?;
+        let address = self.sensor_type.address();
+        match self.sensor_type {
+            GasSensorType::Scd41 => {
+                // read_measurement
+                sensirion_command(address, 0xec05)?;
+                unsafe { vTaskDelay(ms_to_ticks(1)); }
+                let words = sensirion_read_words::<3>(address)?;
+                Ok(Vec::from([
+                    ("co2_ppm", words[0] as f32),
+                    ("temperature", -45.0 + 175.0 * words[1] as f32 / 65535.0),
+                    ("humidity", 100.0 * words[2] as f32 / 65535.0),
+                ]))
+            }
+            GasSensorType::Sgp30 => {
+                // sgp30_measure_iaq
+                sensirion_command(address, 0x2008)?;
+                unsafe { vTaskDelay(ms_to_ticks(12)); }
+                let words = sensirion_read_words::<2>(address)?;
+                Ok(Vec::from([
+                    ("eco2_ppm", words[0] as f32),
+                    ("tvoc_ppb", words[1] as f32),
+                ]))
+            }
+        }
+    }
+}
+
+struct GasSensorArray {
+    sensors: Vec<GasSensor>,
+}
+
+impl GasSensorArray {
+    fn init() -> Self {
+        let mut sensors: Vec<GasSensor> = GAS_SENSORS.iter()
+            .map(|&(mux_channel, sensor_type)| GasSensor { mux_channel, sensor_type, initialized: false })
+            .collect();
+        for sensor in sensors.iter_mut() {
+            match sensor.init() {
+                Ok(()) => info!("Gas sensor {} initialized", sensor.key_prefix()),
+                Err(e) => error!("Failed to init gas sensor {}: {:?}", sensor.key_prefix(), e),
+            }
+        }
+        Self { sensors }
+    }
+
+    // A sensor that fails to init or read only drops its own keys; init is retried on the next read
+    fn read_all(&mut self) -> serde_json::Map<String, Value> {
+        let mut values = serde_json::Map::new();
+        for sensor in self.sensors.iter_mut() {
+            if !sensor.initialized {
+                if let Err(e) = sensor.init() {
+                    error!("Gas sensor {} still unavailable: {:?}", sensor.key_prefix(), e);
+                    continue;
+                }
+            }
+            match sensor.read() {
+                Ok(readings) => {
+                    let prefix = sensor.key_prefix();
+                    for (name, value) in readings {
+                        info!("{} {}: {:.2}", prefix, name, value);
+                        values.insert(format!("{}_{}", prefix, name), json!(round_to(value, 2)));
+                    }
+                }
+                Err(e) => error!("Failed to read gas sensor {}: {:?}", sensor.key_prefix(), e),
+            }
+        }
+        values
+    }
+}
+
+fn select_mux_channel(channel: u8) -> Result<()> {
+    if channel > 7 {
+        return Err(anyhow!("Invalid I2C mux channel {}", channel));
+    }
+    i2c_write_raw(I2C_MUX_ADDRESS, &[1 << channel])?;
+    unsafe { vTaskDelay(ms_to_ticks(I2C_MUX_SWITCH_DELAY_MS)); }
+    Ok(())
+}
+
+// The BME280 driver owns the I2cDriver, so the gas sensors and BME280 normal mode talk to the same port through
+// the IDF driver directly
+fn i2c_write_raw(address: u8, data: &[u8]) -> Result<()> {
+    let err = unsafe {
+        i2c_master_write_to_device(GAS_SENSOR_I2C_PORT, address, data.as_ptr(), data.len(), ms_to_ticks(GAS_SENSOR_I2C_TIMEOUT_MS))
+    };
+    if err != ESP_OK {
+        return Err(anyhow!("I2C write to 0x{:02x} failed, error code: {}", address, err));
+    }
+    Ok(())
+}
+
+fn i2c_read_registers(address: u8, register: u8, buffer: &mut [u8]) -> Result<()> {
+    let err = unsafe {
+        i2c_master_write_read_device(GAS_SENSOR_I2C_PORT, address, &register, 1, buffer.as_mut_ptr(), buffer.len(),
+            ms_to_ticks(GAS_SENSOR_I2C_TIMEOUT_MS))
+    };
+    if err != ESP_OK {
+        return Err(anyhow!("I2C read of register 0x{:02x} from 0x{:02x} failed, error code: {}", register, address, err));
+    }
+    Ok(())
+}
+
+fn sensirion_command(address: u8, command: u16) -> Result<()> {
+    i2c_write_raw(address, &command.to_be_bytes())
+}
+
+// Sensirion sensors send each 16-bit word followed by a CRC-8 (polynomial 0x31, init 0xff)
+fn sensirion_read_words<const N: usize>(address: u8) -> Result<[u16; N]> {
+    let mut buffer = [0u8; 9];
+    let len = N * 3;
+    let err = unsafe {
+        i2c_master_read_from_device(GAS_SENSOR_I2C_PORT, address, buffer.as_mut_ptr(), len, ms_to_ticks(GAS_SENSOR_I2C_TIMEOUT_MS))
+    };
+    if err != ESP_OK {
+        return Err(anyhow!("I2C read from 0x{:02x} failed, error code: {}", address, err));
+    }
+    let mut words = [0u16; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        let chunk = &buffer[i * 3..i * 3 + 3];
+        if sensirion_crc(&chunk[..2]) != chunk[2] {
+            return Err(anyhow!("CRC mismatch in word {} from 0x{:02x}", i, address));
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(words)
+}
+
+fn sensirion_crc(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xff;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[derive(PartialEq)]
+enum OtaState {
+    Idle,
+    Downloading,
+    Downloaded,
+    Verifying,
+    Updating,
+    Updated,
+    Failed(String),
+}
+
+impl OtaState {
+    fn name(&self) -> &'static str {
+        match self {
+            OtaState::Idle => "IDLE",
+            OtaState::Downloading => "DOWNLOADING",
+            OtaState::Downloaded => "DOWNLOADED",
+            OtaState::Verifying => "VERIFYING",
+            OtaState::Updating => "UPDATING",
+            OtaState::Updated => "UPDATED",
+            OtaState::Failed(_) => "FAILED",
+        }
+    }
+
+    // An update is in flight and owns the OTA partition
+    fn is_active(&self) -> bool {
+        matches!(self, OtaState::Downloading | OtaState::Downloaded | OtaState::Verifying | OtaState::Updating)
+    }
+
+    // The last update finished, successfully or not, and nothing more will happen until a new one starts
+    fn is_terminal(&self) -> bool {
+        matches!(self, OtaState::Updated | OtaState::Failed(_))
+    }
+
+    fn is_failed(&self) -> bool {
+        matches!(self, OtaState::Failed(_))
+    }
+}
+
+struct OtaEventLog {
+    entries: Vec<String>,
+    mounted: bool,
+}
+
+impl OtaEventLog {
+    fn open() -> Self {
+        let mut log = Self { entries: Vec::new(), mounted: false };
+        if !OTA_EVENT_LOG_ENABLED {
+            return log;
+        }
+        match Self::mount() {
+            Ok(()) => {
+                log.mounted = true;
+                log.load();
+                info!("OTA event log mounted, {} entries retained", log.entries.len());
+            }
+            Err(e) => error!("OTA event log unavailable: {:?}", e),
+        }
+        log
+    }
+
+    fn mount() -> Result<()> {
+        let conf = esp_vfs_spiffs_conf_t {
+            base_path: SPIFFS_BASE_PATH.as_ptr(),
+            partition_label: SPIFFS_PARTITION_LABEL.as_ptr(),
+            max_files: 2,
+            format_if_mount_failed: true,
+        };
+        let res = unsafe { esp_vfs_spiffs_register(&conf) };
+        if res != ESP_OK {
+            return Err(anyhow!("Failed to mount SPIFFS partition: {}", res));
+        }
+        Ok(())
+    }
+
+    fn load(&mut self) {
+        unsafe {
+            let file = fopen(OTA_EVENT_LOG_PATH.as_ptr(), c"r".as_ptr());
+            if file.is_null() {
+                return;
+            }
+            let mut line = [0 as c_char; 192];
+            while !fgets(line.as_mut_ptr(), line.len() as i32, file).is_null() {
+                let entry = CStr::from_ptr(line.as_ptr()).to_str().unwrap_or("").trim_end();
+                if !entry.is_empty() {
+                    self.push(entry.to_string());
+                }
+            }
+            fclose(file);
+        }
+    }
+
+    fn push(&mut self, entry: String) {
+        if self.entries.len() >= OTA_EVENT_LOG_CAPACITY {
+            self.entries.remove(0);
+        }
+        self.entries.push(entry);
+    }
+
+    fn record(&mut self, event: &str) {
+        if !OTA_EVENT_LOG_ENABLED {
+            return;
+        }
+        let uptime_ms = unsafe { esp_timer_get_time() } / 1000;
+        let entry = format!("{} up={}ms {}", current_timestamp_ms(), uptime_ms, event);
+        info!("OTA event: {}", entry);
+        self.push(entry);
+        if self.mounted {
+            if let Err(e) = self.persist() {
+                error!("Failed to persist OTA event log: {:?}", e);
+            }
+        }
+    }
+
+    fn persist(&self) -> Result<()> {
+        unsafe {
+            let file = fopen(OTA_EVENT_LOG_PATH.as_ptr(), c"w".as_ptr());
+            if file.is_null() {
+                return Err(anyhow!("Failed to open OTA event log for writing"));
+            }
+            for entry in &self.entries {
+                let line = CString::new(format!("{}\n", entry))?;
+                if fputs(line.as_ptr(), file) < 0 {
+                    fclose(file);
+                    return Err(anyhow!("Failed to write OTA event log"));
+                }
+            }
+            fclose(file);
+        }
+        Ok(())
+    }
+}
+
+// Only the firmware keys are kept; any other shared attributes are skipped without being allocated. Every field
+// goes through lenient_attribute, so one value of an unexpected type (ThingsBoard may send "true" or "1024" as
+// strings) is dropped on its own instead of failing the whole message. Field names must match the FW_*_ATTR
+// constants.
+#[derive(Deserialize)]
+struct FirmwareAttributes {
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_title: Option<String>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_version: Option<String>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_size: Option<u32>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_checksum: Option<String>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_checksum_algorithm: Option<String>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_force_update: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_url: Option<String>,
+    #[serde(default, deserialize_with = "lenient_attribute")]
+    fw_tag: Option<String>,
+}
+
+// A shared attribute type and the JSON values accepted for it
+trait AttributeValue: Sized {
+    const EXPECTED: &'static str;
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl AttributeValue for String {
+    const EXPECTED: &'static str = "a string";
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(text) => Some(text.clone()),
+            Value::Number(number) => Some(number.to_string()),
+            _ => None,
+        }
+    }
+}
+
+// Accepts integers, whole floats ("fw_size": 1024.0) and numeric strings
+impl AttributeValue for u32 {
+    const EXPECTED: &'static str = "a whole number";
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let size = match value {
+            Value::Number(number) => number.as_f64()?,
+            Value::String(text) => text.trim().parse::<f64>().ok()?,
+            _ => return None,
+        };
+        if size >= 0.0 && size <= u32::MAX as f64 && (size as u32) as f64 == size {
+            Some(size as u32)
+        } else {
+            None
+        }
+    }
+}
+
+impl AttributeValue for bool {
+    const EXPECTED: &'static str = "a boolean";
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(flag) => Some(*flag),
+            Value::String(text) if text.trim().eq_ignore_ascii_case("true") => Some(true),
+            Value::String(text) if text.trim().eq_ignore_ascii_case("false") => Some(false),
+            _ => None,
+        }
+    }
+}
+
+// A null counts as absent; a value that does not convert is logged and skipped
+fn lenient_attribute<'de, D: serde::Deserializer<'de>, T: AttributeValue>(deserializer: D) -> core::result::Result<Option<T>, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    if value.is_null() {
+        return Ok(None);
+    }
+    let converted = T::from_value(&value);
+    if converted.is_none() {
+        warn!("Ignoring shared attribute value {}, expected {}", value, T::EXPECTED);
+    }
+    Ok(converted)
+}
+
+impl FirmwareAttributes {
+    fn is_empty(&self) -> bool {
+        self.fw_title.is_none() && self.fw_version.is_none() && self.fw_size.is_none()
+            && self.fw_checksum.is_none() && self.fw_checksum_algorithm.is_none() && self.fw_force_update.is_none()
+            && self.fw_url.is_none() && self.fw_tag.is_none()
+    }
+}
+
+// Whether firmware carrying `tag` is meant for this unit's rollout group
+fn fw_tag_targets_device(tag: &str, fw_title: &str, fw_version: &str) -> bool {
+    tag == format!("{} {}", fw_title, fw_version) || DEFAULT_CONFIG.fw_tag == Some(tag)
+}
+
+#[derive(Deserialize)]
+struct AttributesResponse {
+    shared: Option<FirmwareAttributes>,
+}
+
+fn parse_attribute_payload<'a, T: Deserialize<'a>>(payload: &'a str) -> Result<T> {
+    if payload.len() > MAX_ATTRIBUTE_PAYLOAD_BYTES {
+        return Err(anyhow!("Attribute payload of {} bytes exceeds the {} byte limit", payload.len(), MAX_ATTRIBUTE_PAYLOAD_BYTES));
+    }
+    serde_json::from_str(payload).map_err(|e| anyhow!("Malformed attribute payload: {}", e))
+}
+
+// Solicited responses wrap the keys in a `shared` object while unsolicited pushes carry them at the top level;
+// the wrapped shape is tried first
+fn parse_firmware_attributes(payload: &str) -> Result<FirmwareAttributes> {
+    let response: AttributesResponse = parse_attribute_payload(payload)?;
+    match response.shared {
+        Some(attributes) => Ok(attributes),
+        None => parse_attribute_payload(payload),
+    }
+}
+
+// The firmware whose update failed last, held back until the cooldown ends
+struct OtaCooldown {
+    fw_title: String,
+    fw_version: String,
+    started_tick: u32,
+    // Polls that re-advertised the failed firmware, which set the poll backoff
+    skipped_polls: u32,
+}
+
+impl OtaCooldown {
+    fn remaining_ms(&self) -> u32 {
+        let elapsed = ticks_elapsed(unsafe { xTaskGetTickCount() }, self.started_tick).map_or(u32::MAX, ticks_to_ms);
+        OTA_RETRY_COOLDOWN_MS.saturating_sub(elapsed)
+    }
+
+    fn holds_back(&self, fw_title: &str, fw_version: &str) -> bool {
+        self.fw_title == fw_title.trim() && self.fw_version == fw_version.trim() && self.remaining_ms() > 0
+    }
+}
+
+// Called after every chunk written to flash with (chunk index just written, bytes received, image size)
+type OtaProgressCallback = Box<dyn FnMut(u32, usize, u32)>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum OtaTransport {
+    Mqtt,
+    Http,
+}
+
+// Time spent in flash operations during one download, to tell slow flash apart from a slow network
+#[derive(Clone, Copy, Default)]
+struct FlashTimings {
+    erase_ticks: u32,
+    write_ticks: u32,
+    writes: u32,
+    end_ticks: u32,
+}
+
+impl FlashTimings {
+    fn to_json(self) -> Value {
+        json!({
+            "flash_erase_ms": ticks_to_ms(self.erase_ticks),
+            "flash_write_ms": ticks_to_ms(self.write_ticks),
+            "flash_writes": self.writes,
+            "flash_end_ms": ticks_to_ms(self.end_ticks)
+        })
+    }
+}
+
+// What the status page shows of the OTA manager. The manager refreshes it as its state changes, so the HTTP
+// server task only ever reads this copy.
+#[cfg(feature = "http-status")]
+#[derive(Clone, Default)]
+struct OtaStatusSnapshot {
+    fw_state: &'static str,
+    fw_progress: Option<f32>,
+    firmware_identity: Value,
+}
+
+struct OtaManager {
+    current_fw_title: String,
+    current_fw_version: String,
+    fw_title: Option<String>,
+    fw_version: Option<String>,
+    fw_size: Option<u32>,
+    fw_checksum: Option<String>,
+    fw_checksum_algorithm: Option<String>,
+    fw_force_update: bool,
+    fw_url: Option<String>,
+    fw_tag: Option<String>,
+    forced_update: bool,
+    // Set once DEFERRED_WEAK_SIGNAL has been reported, until a download starts
+    deferred_weak_signal: bool,
+    transport: OtaTransport,
+    http_client: esp_http_client_handle_t,
+    // Read buffer for HTTP downloads, allocated while one is open
+    http_buffer: Vec<u8>,
+    ota_state: OtaState,
+    request_id: u32,
+    firmware_request_id: u32,
+    current_chunk: u32,
+    ota_handle: esp_ota_handle_t,
+    ota_partition: *const esp_partition_t,
+    received_size: usize,
+    download_started_tick: u32,
+    // Message id of the UPDATED report, acknowledged before the restart
+    final_report_msg_id: Option<i32>,
+    sha256_hasher: Sha256,
+    image_buffer: Vec<u8>,
+    partial_firmware_data: Vec<u8>,
+    chunk_buffer: Vec<(u32, Vec<u8>)>,
+    chunk_size: usize,
+    last_chunk_received: u32,
+    last_chunk_written: u32,
+    throughput_bps: Option<f32>,
+    throughput_samples: u32,
+    flash_timings: FlashTimings,
+    telemetry_counter: u32,
+    restart_pending: bool,
+    nvs: Option<EspDefaultNvs>,
+    event_log: OtaEventLog,
+    progress_callback: Option<OtaProgressCallback>,
+    cooldown: Option<OtaCooldown>,
+    // Leaked, since the status page reads it for the whole run
+    #[cfg(feature = "http-status")]
+    status_snapshot: &'static Guarded<OtaStatusSnapshot>,
+}
+
+impl OtaManager {
+    fn new(nvs: Option<EspDefaultNvs>, event_log: OtaEventLog) -> Self {
+        unsafe {
+            let otadata_partition = esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_OTA,
+                core::ptr::null()
+            );
+            if !otadata_partition.is_null() {
+                info!("Found otadata partition at {:p}", otadata_partition);
+            } else {
+                error!("No otadata partition found");
+            }
+
+            let mut ota_partitions_found = 0;
+            for subtype in &[esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_0, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_1] {
+                let mut iterator = esp_partition_find(
+                    esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+                    *subtype,
+                    core::ptr::null()
+                );
+                while !iterator.is_null() {
+                    let partition = esp_partition_get(iterator);
+                    let label = core::ffi::CStr::from_ptr((*partition).label.as_ptr()).to_str().unwrap_or("unknown");
+                    info!("Found OTA partition: {}, subtype: {:?}, address: 0x{:x}, size: 0x{:x}",
+                        label, *subtype, (*partition).address, (*partition).size);
+                    ota_partitions_found += 1;
+                    iterator = esp_partition_next(iterator);
+                }
+                esp_partition_iterator_release(iterator);
+            }
+            if ota_partitions_found < 2 {
+                error!("Insufficient OTA partitions found: {}. Need at least 2 for OTA.", ota_partitions_found);
+            } else {
+                info!("Found {} OTA partitions, sufficient for OTA", ota_partitions_found);
+            }
+
+            let running_partition = esp_ota_get_running_partition();
+            if !running_partition.is_null() {
+                let label = core::ffi::CStr::from_ptr((*running_partition).label.as_ptr()).to_str().unwrap_or("unknown");
+                info!("Current running partition: {}, address: 0x{:x}, size: 0x{:x}",
+                    label, (*running_partition).address, (*running_partition).size);
+            } else {
+                error!("No running partition detected");
+            }
+        }
+
+        let manager = Self {
+            current_fw_title: "Weather Station".to_string(),
+            current_fw_version: "V1.0".to_string(),
+            fw_title: None,
+            fw_version: None,
+            fw_size: None,
+            fw_checksum: None,
+            fw_checksum_algorithm: None,
+            fw_force_update: false,
+            fw_url: None,
+            fw_tag: None,
+            forced_update: false,
+            deferred_weak_signal: false,
+            transport: OtaTransport::Mqtt,
+            http_client: core::ptr::null_mut(),
+            http_buffer: Vec::new(),
+            ota_state: OtaState::Idle,
+            request_id: 0,
+            firmware_request_id: 0,
+            current_chunk: 0,
+            ota_handle: 0,
+            ota_partition: core::ptr::null(),
+            received_size: 0,
+            download_started_tick: 0,
+            final_report_msg_id: None,
+            sha256_hasher: Sha256::new(),
+            image_buffer: Vec::new(),
+            partial_firmware_data: Vec::new(),
+            chunk_buffer: Vec::with_capacity(10),
+            chunk_size: OTA_CHUNK_SIZE,
+            last_chunk_received: 0,
+            last_chunk_written: 0,
+            throughput_bps: None,
+            throughput_samples: 0,
+            flash_timings: FlashTimings::default(),
+            telemetry_counter: 0,
+            restart_pending: false,
+            nvs,
+            event_log,
+            progress_callback: None,
+            cooldown: None,
+            #[cfg(feature = "http-status")]
+            status_snapshot: Box::leak(Box::new(Guarded::new(OtaStatusSnapshot::default()))),
+        };
+        manager.update_status_snapshot();
+        manager
+    }
+
+    /// Hook for progress presentation (display, LEDs, custom logging). The callback runs on the main loop
+    /// between chunks, so it must be quick.
+    #[allow(dead_code)] // extension point, not registered by the stock firmware
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(u32, usize, u32) + 'static) {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    // Normal cadence, backed off while a cooldown keeps refusing the advertised firmware. The wait never runs
+    // past the end of the cooldown, so the retry is picked up on time.
+    fn firmware_info_poll_interval_ms(&mut self) -> u32 {
+        let Some(cooldown) = &self.cooldown else {
+            return DEFAULT_CONFIG.firmware_info_poll_interval_ms;
+        };
+        let remaining_ms = cooldown.remaining_ms();
+        if remaining_ms == 0 {
+            info!("OTA retry cooldown over, polling firmware info at the normal interval");
+            self.cooldown = None;
+            return DEFAULT_CONFIG.firmware_info_poll_interval_ms;
+        }
+        let backoff_ms = DEFAULT_CONFIG.firmware_info_poll_interval_ms
+            .saturating_mul(1 << cooldown.skipped_polls.min(16))
+            .min(FIRMWARE_INFO_POLL_BACKOFF_MAX_MS);
+        backoff_ms.min(remaining_ms.max(DEFAULT_CONFIG.firmware_info_poll_interval_ms))
+    }
+
+    /// Download progress in percent, or `None` while idle or before the image size is known.
+    pub fn progress_percent(&self) -> Option<f32> {
+        if self.ota_state == OtaState::Idle {
+            return None;
+        }
+        match self.fw_size {
+            Some(fw_size) if fw_size > 0 => Some(self.received_size as f32 / fw_size as f32 * 100.0),
+            _ => None,
+        }
+    }
+
+    /// Smoothed download rate in bytes per second, once at least one chunk interval has been measured.
+    pub fn throughput_bps(&self) -> Option<f32> {
+        self.throughput_bps
+    }
+
+    /// Estimated seconds until the download completes, or `None` while the rate is still being established.
+    pub fn eta_seconds(&self) -> Option<u32> {
+        if self.throughput_samples < OTA_ETA_MIN_SAMPLES {
+            return None;
+        }
+        let (fw_size, throughput) = (self.fw_size?, self.throughput_bps?);
+        if throughput <= 0.0 {
+            return None;
+        }
+        let remaining = (fw_size as usize).saturating_sub(self.received_size);
+        Some((remaining as f32 / throughput) as u32)
+    }
+
+    fn record_chunk_throughput(&mut self, bytes: usize) {
+        let now = unsafe { xTaskGetTickCount() };
+        let elapsed_ticks = ticks_elapsed(now, self.last_chunk_written).unwrap_or(0);
+        self.last_chunk_written = now;
+        // No sample without a usable interval, including a last chunk time that is ahead of the counter
+        if elapsed_ticks == 0 {
+            return;
+        }
+        let sample = bytes as f32 * configTICK_RATE_HZ as f32 / elapsed_ticks as f32;
+        self.throughput_bps = Some(match self.throughput_bps {
+            Some(ema) => OTA_THROUGHPUT_EMA_ALPHA * sample + (1.0 - OTA_THROUGHPUT_EMA_ALPHA) * ema,
+            None => sample,
+        });
+        self.throughput_samples += 1;
+    }
+
+    /// Name of the current OTA state, as reported in `fw_state` telemetry.
+    pub fn ota_state_str(&self) -> &'static str {
+        if !OTA_ENABLED {
+            return "DISABLED";
+        }
+        self.ota_state.name()
+    }
+
+    fn set_state(&mut self, state: OtaState) {
+        match &state {
+            OtaState::Failed(error) => self.event_log.record(&format!("FAILED: {}", error)),
+            other => self.event_log.record(other.name()),
+        }
+        if state.is_failed() && self.ota_state.is_active() {
+            error!("OTA failed while {}", self.ota_state.name());
+        }
+        if state.is_failed() && !self.ota_state.is_failed() {
+            LifetimeCounter::OtaFailures.increment();
+        } else if state == OtaState::Updated && self.ota_state != OtaState::Updated {
+            LifetimeCounter::OtaSuccesses.increment();
+        }
+        if state.is_terminal() {
+            self.image_buffer = Vec::new();
+        }
+        if let (true, Some(fw_title), Some(fw_version)) = (state.is_failed(), &self.fw_title, &self.fw_version) {
+            self.cooldown = Some(OtaCooldown {
+                fw_title: fw_title.trim().to_string(),
+                fw_version: fw_version.trim().to_string(),
+                started_tick: unsafe { xTaskGetTickCount() },
+                skipped_polls: 0,
+            });
+        }
+        self.ota_state = state;
+        self.update_status_snapshot();
+    }
+
+    // Called on every state change and written chunk
+    fn update_status_snapshot(&self) {
+        #[cfg(feature = "http-status")]
+        {
+            let snapshot = OtaStatusSnapshot {
+                fw_state: self.ota_state_str(),
+                fw_progress: self.progress_percent(),
+                firmware_identity: self.firmware_identity_json(),
+            };
+            self.status_snapshot.with(|status| *status = snapshot);
+        }
+    }
+
+    // Catches malformed checksums before the download instead of failing verification after it
+    fn validate_checksum_format(&self) -> Result<()> {
+        let algorithm = self.fw_checksum_algorithm.as_deref().unwrap_or("SHA256");
+        // Checked even without a checksum: the server expects verification this firmware cannot do
+        let expected_len = match algorithm.to_ascii_uppercase().as_str() {
+            "SHA256" => 64,
+            _ => return Err(anyhow!("Unsupported checksum algorithm: {}", algorithm)),
+        };
+        let Some(checksum) = self.fw_checksum.as_deref() else {
+            return Ok(());
+        };
+        if checksum.len() != expected_len {
+            return Err(anyhow!("Malformed {}: expected {} hex characters for {}, got {}", FW_CHECKSUM_ATTR, expected_len, algorithm, checksum.len()));
+        }
+        if let Some(bad) = checksum.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Malformed {}: '{}' is not a hex digit", FW_CHECKSUM_ATTR, bad));
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: OtaMessage, mqtt_client: *mut esp_mqtt_client) {
+        match message {
+            OtaMessage::SharedAttributes(attributes) => {
+                info!("OTA response data: {}", attributes);
+                if let Err(e) = self.handle_shared_attributes(&attributes, mqtt_client) {
+                    error!("Failed to handle OTA attributes: {:?}", e);
+                }
+            }
+            OtaMessage::AttributeUpdate(attributes) => {
+                if let Err(e) = self.handle_attribute_update(&attributes, mqtt_client) {
+                    error!("Failed to handle attribute update: {:?}", e);
+                }
+            }
+            OtaMessage::FirmwareFragment { topic, total_len, offset, data } => {
+                self.handle_firmware_fragment(&topic, total_len, offset, &data, mqtt_client);
+            }
+        }
+    }
+
+    fn handle_shared_attributes(&mut self, attributes: &str, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        let shared_attrs = parse_firmware_attributes(attributes)?;
+        info!("Raw attributes received: {}", attributes);
+
+        // A full response lists every configured key, so a missing URL means MQTT chunks and a missing tag an
+        // untargeted package
+        if !self.ota_state.is_active() {
+            self.fw_url = None;
+            self.fw_tag = None;
+        }
+        self.apply_firmware_attributes(&shared_attrs, mqtt_client)
+    }
+
+    // Unsolicited pushes on the attributes topic usually carry only the changed keys
+    fn handle_attribute_update(&mut self, attributes: &str, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        let attrs = parse_firmware_attributes(attributes)?;
+        info!("Attribute update pushed: {}", attributes);
+
+        if attrs.is_empty() {
+            info!("Attribute update contains no firmware attributes, ignoring");
+            return Ok(());
+        }
+        if self.ota_state.is_active() {
+            info!("Update in progress, pushed firmware attributes will be picked up by the next poll");
+            return Ok(());
+        }
+        self.apply_firmware_attributes(&attrs, mqtt_client)
+    }
+
+    fn apply_firmware_attributes(&mut self, shared_attrs: &FirmwareAttributes, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        if let Some(fw_title) = &shared_attrs.fw_title {
+            self.fw_title = Some(fw_title.trim().to_string());
+            info!("Received fw_title: '{}'", fw_title);
+        }
+        if let Some(fw_version) = &shared_attrs.fw_version {
+            self.fw_version = Some(fw_version.trim().to_string());
+            info!("Received fw_version: '{}'", fw_version);
+        }
+        if let Some(fw_size) = shared_attrs.fw_size {
+            self.fw_size = Some(fw_size);
+            info!("Received fw_size: {}", fw_size);
+        }
+        if let Some(fw_checksum) = &shared_attrs.fw_checksum {
+            self.fw_checksum = Some(fw_checksum.trim().to_ascii_lowercase());
+            info!("Received fw_checksum: '{}'", fw_checksum);
+        }
+        if let Some(fw_checksum_alg) = &shared_attrs.fw_checksum_algorithm {
+            self.fw_checksum_algorithm = Some(fw_checksum_alg.trim().to_string());
+            info!("Received fw_checksum_algorithm: '{}'", fw_checksum_alg);
+        }
+        if let Some(fw_url) = &shared_attrs.fw_url {
+            self.fw_url = Some(fw_url.trim().to_string()).filter(|url| !url.is_empty());
+            info!("Received fw_url: '{}'", fw_url);
+        }
+        if let Some(fw_tag) = &shared_attrs.fw_tag {
+            self.fw_tag = Some(fw_tag.trim().to_string()).filter(|tag| !tag.is_empty());
+            info!("Received fw_tag: '{}'", fw_tag);
+        }
+        self.fw_force_update = shared_attrs.fw_force_update.unwrap_or(false);
+        if self.fw_force_update {
+            info!("Received fw_force_update: true");
         }
 
         let mut result = Ok(());
         if let (Some(fw_title), Some(fw_version)) = (&self.fw_title, &self.fw_version) {
             info!("Comparing fw_title: '{}' vs '{}', fw_version: '{}' vs '{}'", 
                 fw_title, self.current_fw_title, fw_version, self.current_fw_version);
-            if fw_title.trim() != self.current_fw_title.trim() || fw_version.trim() != self.current_fw_version.trim() {
-                info!("New firmware available: {} {}, starting download", fw_title, fw_version);
-                self.ota_state = OtaState::Downloading;
+            let version_changed = fw_title.trim() != self.current_fw_title.trim() || fw_version.trim() != self.current_fw_version.trim();
+            // Same product advertising an older version, e.g. V1.0 while V2.0 is running
+            let downgrade = fw_title.trim() == self.current_fw_title.trim() && is_version_downgrade(fw_version, &self.current_fw_version);
+            let forced = (!version_changed || downgrade) && self.fw_force_update;
+            let foreign_tag = self.fw_tag.as_deref().filter(|tag| !fw_tag_targets_device(tag, fw_title, fw_version));
+            if let Some(tag) = foreign_tag.filter(|_| version_changed || forced) {
+                info!("Firmware {} {} is tagged '{}', not for this device's group ({}), skipping",
+                    fw_title, fw_version, tag, DEFAULT_CONFIG.fw_tag.unwrap_or("none"));
+            } else if downgrade && !forced {
+                info!("Refusing downgrade from {} to {}; set {} to install it anyway",
+                    self.current_fw_version, fw_version, FW_FORCE_UPDATE_ATTR);
+            } else if forced && self.forced_image_already_applied() {
+                info!("Forced reflash of this image was already applied; clear {} to stop re-flashing", FW_FORCE_UPDATE_ATTR);
+            } else if let Some(cooldown) = self.cooldown.as_mut().filter(|cooldown| cooldown.holds_back(fw_title, fw_version)) {
+                cooldown.skipped_polls += 1;
+                info!("Update to {} {} failed recently, retrying after the cooldown ends in {} s",
+                    fw_title, fw_version, cooldown.remaining_ms() / 1000);
+            } else if let Some((rssi, min_rssi)) = (version_changed || forced).then(weak_signal).flatten() {
+                info!("Deferring update to {} {}: WiFi RSSI {} dBm is below {} dBm", fw_title, fw_version, rssi, min_rssi);
+                if !self.deferred_weak_signal {
+                    let payload = json!({
+                        FW_STATE_ATTR: "DEFERRED_WEAK_SIGNAL",
+                        "fw_deferred_rssi": rssi,
+                        "fw_min_rssi": min_rssi
+                    }).to_string();
+                    Self::mqtt_publish(mqtt_client, OTA_PROGRESS_TOPIC.unwrap_or(OTA_TELEMETRY_TOPIC), &payload)?;
+                    self.deferred_weak_signal = true;
+                }
+            } else if version_changed || forced {
+                self.cooldown = None;
+                self.deferred_weak_signal = false;
+                // Nothing has been erased yet, so a bad checksum attribute fails the update before the download
+                if let Err(e) = self.validate_checksum_format() {
+                    let failed = OtaState::Failed(e.to_string());
+                    if self.ota_state != failed {
+                        self.set_state(failed);
+                        self.send_ota_telemetry(mqtt_client)?;
+                    }
+                    return Err(e);
+                }
+                if forced && downgrade {
+                    info!("FORCED DOWNGRADE requested via {}: installing {} {} over {}", FW_FORCE_UPDATE_ATTR, fw_title, fw_version, self.current_fw_version);
+                } else if forced {
+                    info!("FORCED REFLASH requested via {}: re-installing {} {} despite matching version", FW_FORCE_UPDATE_ATTR, fw_title, fw_version);
+                } else {
+                    info!("New firmware available: {} {}, starting download", fw_title, fw_version);
+                }
+                self.forced_update = forced;
+                self.set_state(OtaState::Downloading);
                 self.firmware_request_id += 1;
                 self.current_chunk = 0;
                 self.received_size = 0;
                 self.sha256_hasher = Sha256::new();
+                self.image_buffer = Vec::new();
                 self.chunk_buffer.clear();
                 self.last_chunk_received = unsafe { xTaskGetTickCount() };
+                self.last_chunk_written = self.last_chunk_received;
+                self.throughput_bps = None;
+                self.throughput_samples = 0;
+                self.flash_timings = FlashTimings::default();
+                self.download_started_tick = self.last_chunk_received;
+                unsafe {
+                    self.ota_partition = esp_ota_get_next_update_partition(core::ptr::null());
+                    if self.ota_partition.is_null() {
+                        error!("esp_ota_get_next_update_partition failed. Attempting manual partition selection...");
+                        let running_partition = esp_ota_get_running_partition();
+                        if !running_partition.is_null() {
+                            let label = core::ffi::CStr::from_ptr((*running_partition).label.as_ptr()).to_str().unwrap_or("unknown");
+                            info!("Running partition: {}, address: 0x{:x}", label, (*running_partition).address);
+                        } else {
+                            error!("No running partition detected");
+                        }
+
+                        for subtype in &[esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_0, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_1] {
+                            let mut iterator = esp_partition_find(
+                                esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+                                *subtype,
+                                core::ptr::null()
+                            );
+                            while !iterator.is_null() {
+                                let partition = esp_partition_get(iterator);
+                                let label = core::ffi::CStr::from_ptr((*partition).label.as_ptr()).to_str().unwrap_or("unknown");
+                                info!("Checking partition: {}, subtype: {:?}, address: 0x{:x}", label, *subtype, (*partition).address);
+                                if !running_partition.is_null() && !same_partition(partition, running_partition) {
+                                    self.ota_partition = partition;
+                                    break;
+                                }
+                                iterator = esp_partition_next(iterator);
+                            }
+                            esp_partition_iterator_release(iterator);
+                            if !self.ota_partition.is_null() {
+                                break;
+                            }
+                        }
+                    }
+
+                    let running_partition = esp_ota_get_running_partition();
+                    if self.ota_partition.is_null() {
+                        error!("No valid OTA partition found for update");
+                        self.set_state(OtaState::Failed("No valid OTA partition found".to_string()));
+                        result = Err(anyhow!("No valid OTA partition found"));
+                    } else if running_partition.is_null() || same_partition(self.ota_partition, running_partition) {
+                        error!("REFUSING TO ERASE: selected OTA partition at 0x{:x} is the running partition or the running partition is unknown",
+                            (*self.ota_partition).address);
+                        self.set_state(OtaState::Failed("Selected OTA partition is the running partition".to_string()));
+                        result = Err(anyhow!("Selected OTA partition is the running partition"));
+                    } else if let Err(e) = check_update_partition(self.ota_partition) {
+                        error!("REFUSING TO ERASE: {}", e);
+                        self.set_state(OtaState::Failed(e.to_string()));
+                        result = Err(e);
+                    } else if let Err(e) = self.check_fw_size_limit() {
+                        error!("Rejecting firmware advertisement: {}", e);
+                        self.set_state(OtaState::Failed(e.to_string()));
+                        result = Err(e);
+                    } else if let Err(e) = self.reserve_image_buffer() {
+                        error!("Cannot buffer the image for verification: {}", e);
+                        self.set_state(OtaState::Failed(e.to_string()));
+                        result = Err(e);
+                    } else {
+                        let label = core::ffi::CStr::from_ptr((*self.ota_partition).label.as_ptr()).to_str().unwrap_or("unknown");
+                        info!("Selected OTA partition: {}, address: 0x{:x}, size: 0x{:x}",
+                            label, (*self.ota_partition).address, (*self.ota_partition).size);
+                        
+                        let erase_start = xTaskGetTickCount();
+                        let res = esp_partition_erase_range(self.ota_partition, 0, (*self.ota_partition).size as usize);
+                        self.flash_timings.erase_ticks = ticks_elapsed(xTaskGetTickCount(), erase_start).unwrap_or(u32::MAX);
+                        debug!("Erased 0x{:x} bytes in {} ms", (*self.ota_partition).size, ticks_to_ms(self.flash_timings.erase_ticks));
+                        if res != ESP_OK {
+                            self.set_state(OtaState::Failed(format!("Failed to erase OTA partition: {}", res)));
+                            result = Err(anyhow!("Failed to erase OTA partition: {}", res));
+                        } else if let Err(e) = self.verify_partition_erased() {
+                            self.set_state(OtaState::Failed(format!("Erase verification failed: {}", e)));
+                            result = Err(e);
+                        } else {
+                            let res = esp_ota_begin(self.ota_partition, self.fw_size.unwrap_or(0) as usize, &mut self.ota_handle);
+                            if res != ESP_OK {
+                                self.set_state(OtaState::Failed(format!("Failed to begin OTA: {}", res)));
+                                result = Err(anyhow!("Failed to begin OTA: {}", res));
+                            } else if self.fw_url.is_some() {
+                                self.transport = OtaTransport::Http;
+                                if let Err(e) = self.start_http_download() {
+                                    self.set_state(OtaState::Failed(format!("Failed to start HTTP download: {}", e)));
+                                    result = Err(e);
+                                }
+                            } else {
+                                self.transport = OtaTransport::Mqtt;
+                                for i in 0..3 {
+                                    if let Err(e) = self.request_firmware_chunk(mqtt_client, self.current_chunk + i) {
+                                        self.set_state(OtaState::Failed(format!("Failed to request firmware chunk: {}", e)));
+                                        result = Err(e);
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if let Err(e) = self.send_ota_telemetry(mqtt_client) {
+                        error!("Failed to send OTA telemetry: {:?}", e);
+                    }
+                }
+            } else {
+                info!("No new firmware detected: title and version match current");
+            }
+        } else {
+            info!("Incomplete firmware attributes: fw_title={:?}, fw_version={:?}", self.fw_title, self.fw_version);
+            result = Err(anyhow!("Incomplete firmware attributes received"));
+        }
+        result
+    }   
+
+    fn forced_image_already_applied(&self) -> bool {
+        let (Some(nvs), Some(checksum)) = (self.nvs.as_ref(), self.fw_checksum.as_ref()) else {
+            return false;
+        };
+        let mut buf = [0u8; 72];
+        matches!(nvs.get_str(NVS_FORCED_CHECKSUM_KEY, &mut buf), Ok(Some(stored)) if stored.eq_ignore_ascii_case(checksum))
+    }
+
+    fn record_forced_image(&mut self, checksum: &str) {
+        if let Some(nvs) = self.nvs.as_mut() {
+            if let Err(e) = nvs.set_str(NVS_FORCED_CHECKSUM_KEY, checksum) {
+                error!("Failed to persist forced image checksum: {:?}", e);
+            }
+        }
+    }
+
+    fn check_fw_size_limit(&self) -> Result<()> {
+        let partition_size = unsafe { (*self.ota_partition).size };
+        let limit = MAX_FW_SIZE.map_or(partition_size, |max| max.min(partition_size));
+        match self.fw_size {
+            Some(fw_size) if fw_size > limit => Err(anyhow!("Advertised fw_size {} exceeds the {} byte limit", fw_size, limit)),
+            _ => Ok(()),
+        }
+    }
+
+    fn reserve_image_buffer(&mut self) -> Result<()> {
+        if OTA_CHECKSUM_MODE != ChecksumMode::Buffered {
+            return Ok(());
+        }
+        let fw_size = self.fw_size.ok_or_else(|| anyhow!("fw_size is required to buffer the image"))? as usize;
+        self.image_buffer.try_reserve_exact(fw_size).map_err(|_| {
+            anyhow!("Not enough free heap to buffer a {} byte image ({} bytes free)", fw_size, unsafe { esp_get_free_heap_size() })
+        })
+    }
+
+    fn verify_partition_erased(&self) -> Result<()> {
+        if ERASE_VERIFY_SAMPLES == 0 {
+            return Ok(());
+        }
+        unsafe {
+            let partition_size = (*self.ota_partition).size as usize;
+            let sample_size = ERASE_VERIFY_SAMPLE_SIZE.min(partition_size);
+            let span = (partition_size - sample_size) as u64;
+            let mut buffer = alloc::vec![0u8; sample_size];
+            for i in 0..ERASE_VERIFY_SAMPLES {
+                let offset = if ERASE_VERIFY_SAMPLES == 1 {
+                    0
+                } else {
+                    (span * i as u64 / (ERASE_VERIFY_SAMPLES - 1) as u64) as usize
+                };
+                let res = esp_partition_read(self.ota_partition, offset, buffer.as_mut_ptr() as *mut c_void, sample_size);
+                if res != ESP_OK {
+                    return Err(anyhow!("Failed to read back partition at offset 0x{:x}: {}", offset, res));
+                }
+                if let Some(pos) = buffer.iter().position(|&b| b != 0xFF) {
+                    return Err(anyhow!("Partition not erased at offset 0x{:x}: found 0x{:02x}", offset + pos, buffer[pos]));
+                }
+            }
+            info!("Erase verified: {} samples of {} bytes read back as 0xFF", ERASE_VERIFY_SAMPLES, sample_size);
+        }
+        Ok(())
+    }
+
+    fn request_firmware_info(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        self.request_id += 1;
+        let request_topic = format!("{}{}", OTA_REQUEST_TOPIC, self.request_id);
+        let payload = json!({
+            "sharedKeys": format!("{},{},{},{},{},{},{},{}",
+                FW_TITLE_ATTR, FW_VERSION_ATTR, FW_SIZE_ATTR, FW_CHECKSUM_ATTR, FW_CHECKSUM_ALG_ATTR, FW_FORCE_UPDATE_ATTR, FW_URL_ATTR,
+                FW_TAG_ATTR)
+        });
+        Self::mqtt_publish(mqtt_client, &request_topic, &payload.to_string())?;
+        info!("Requested firmware info, topic: {}", request_topic);
+        Ok(())
+    }
+
+    fn request_firmware_chunk(&mut self, mqtt_client: *mut esp_mqtt_client, chunk_index: u32) -> Result<()> {
+        if let Some(fw_size) = self.fw_size {
+            if self.received_size >= fw_size as usize {
+                info!("All firmware chunks received, no further requests needed");
+                return Ok(());
+            }
+            // Chunks starting at or past the end would only come back empty
+            if chunk_index as usize * self.chunk_size >= fw_size as usize {
+                return Ok(());
+            }
+        }
+        let topic = render_chunk_topic(OTA_CHUNK_REQUEST_TOPIC_TEMPLATE, self.firmware_request_id, chunk_index);
+        let payload = self.chunk_size.to_string();
+        Self::mqtt_publish(mqtt_client, &topic, &payload)?;
+        info!("Requested firmware chunk {}, topic: {}", chunk_index, topic);
+        Ok(())
+    }
+
+    fn handle_firmware_chunk(&mut self, data: &[u8], chunk_index: u32, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        if chunk_index == self.current_chunk {
+            if data.len() == 0 {
+                return self.handle_empty_firmware_response(self.firmware_request_id, chunk_index, mqtt_client);
+            }
+
+            if let Some(fw_size) = self.fw_size {
+                if self.received_size + data.len() > fw_size as usize {
+                    let reason = format!("chunk {} of {} bytes would exceed fw_size {} ({} already received)",
+                        chunk_index, data.len(), fw_size, self.received_size);
+                    return self.reject_firmware_response(mqtt_client, &reason);
+                }
+            }
+
+            self.received_size += data.len();
+            self.update_status_snapshot();
+            info!("Received chunk {}, size: {}, total received: {}", chunk_index, data.len(), self.received_size);
+            
+            if let (Some(percentage), Some(fw_size)) = (self.progress_percent(), self.fw_size) {
+                info!("Download progress: {:.2}% ({} / {})", percentage, self.received_size, fw_size);
+            }
+            
+            match OTA_CHECKSUM_MODE {
+                ChecksumMode::Streaming => self.sha256_hasher.update(data),
+                ChecksumMode::Buffered => self.image_buffer.extend_from_slice(data),
+            }
+            unsafe {
+                let write_start = xTaskGetTickCount();
+                let res = esp_ota_write(self.ota_handle, data.as_ptr() as *const c_void, data.len());
+                let write_ticks = ticks_elapsed(xTaskGetTickCount(), write_start).unwrap_or(u32::MAX);
+                self.flash_timings.write_ticks = self.flash_timings.write_ticks.saturating_add(write_ticks);
+                self.flash_timings.writes += 1;
+                debug!("Wrote {} bytes to flash in {} ms", data.len(), ticks_to_ms(write_ticks));
+                if res != ESP_OK {
+                    return self.abort_ota(mqtt_client, format!("Failed to write OTA data: {}", res));
+                }
+            }
+
+            self.current_chunk += 1;
+            self.last_chunk_received = unsafe { xTaskGetTickCount() };
+            self.record_chunk_throughput(data.len());
+            if let Some(callback) = self.progress_callback.as_mut() {
+                callback(chunk_index, self.received_size, self.fw_size.unwrap_or(0));
+            }
+            if let Some(fw_size) = self.fw_size {
+                // The last chunk is usually short; finish on the exact byte count rather than waiting for an empty terminator
+                if self.received_size == fw_size as usize {
+                    info!("Received all {} bytes, download complete", fw_size);
+                    self.finish_download(mqtt_client)?;
+                } else {
+                    self.process_buffered_chunks(mqtt_client)?;
+                    if self.ota_state == OtaState::Downloading && self.transport == OtaTransport::Mqtt {
+                        self.request_firmware_chunk(mqtt_client, self.current_chunk)?;
+                    }
+                }
+            }
+            Ok(())
+        } else if chunk_index < self.current_chunk {
+            info!("Ignoring duplicate of already written chunk {}", chunk_index);
+            Ok(())
+        } else {
+            info!("Received out-of-order chunk {}, storing in buffer", chunk_index);
+            self.chunk_buffer.push((chunk_index, data.to_vec()));
+            self.chunk_buffer.sort_by_key(|&(index, _)| index);
+            self.process_buffered_chunks(mqtt_client)?;
+            Ok(())
+        }
+    }
+
+    fn start_http_download(&mut self) -> Result<()> {
+        let url = self.fw_url.clone().ok_or_else(|| anyhow!("No {} advertised", FW_URL_ATTR))?;
+        let fw_size = self.fw_size.ok_or_else(|| anyhow!("{} is required for HTTP downloads", FW_SIZE_ATTR))?;
+        let url_cstr = CString::new(url.as_str())?;
+        unsafe {
+            let config = esp_http_client_config_t {
+                url: url_cstr.as_ptr(),
+                method: esp_http_client_method_t_HTTP_METHOD_GET,
+                timeout_ms: OTA_HTTP_TIMEOUT_MS,
+                crt_bundle_attach: Some(esp_crt_bundle_attach),
+                ..Default::default()
+            };
+            let client = esp_http_client_init(&config);
+            if client.is_null() {
+                return Err(anyhow!("Failed to initialize HTTP client"));
+            }
+            self.http_client = client;
+
+            let res = esp_http_client_open(client, 0);
+            if res != ESP_OK {
+                self.close_http_download();
+                return Err(anyhow!("Failed to open {}: {}", url, res));
+            }
+            let content_length = esp_http_client_fetch_headers(client);
+            if content_length < 0 {
+                self.close_http_download();
+                return Err(anyhow!("Failed to read the response headers from {}: {}", url, content_length));
+            }
+            let status = esp_http_client_get_status_code(client);
+            if status != 200 {
+                self.close_http_download();
+                return Err(anyhow!("Firmware download returned HTTP status {}", status));
+            }
+            if content_length > 0 && content_length != fw_size as i64 {
+                self.close_http_download();
+                return Err(anyhow!("Server sends {} bytes but {} is {}", content_length, FW_SIZE_ATTR, fw_size));
+            }
+        }
+        self.http_buffer = alloc::vec![0u8; self.chunk_size];
+        info!("Streaming firmware over HTTP from {}", url);
+        Ok(())
+    }
+
+    // Reads the next slice of an HTTP download through the same write, hash and completion path as MQTT chunks
+    fn poll_http_download(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        if self.transport != OtaTransport::Http || self.http_client.is_null() {
+            return Ok(());
+        }
+        if self.ota_state != OtaState::Downloading {
+            self.close_http_download();
+            return Ok(());
+        }
+        // Taken for the poll, since each slice read into it is handed back to self
+        let mut buffer = core::mem::take(&mut self.http_buffer);
+        let mut read_this_poll = 0;
+        while read_this_poll < OTA_HTTP_BYTES_PER_POLL && self.ota_state == OtaState::Downloading {
+            let read = unsafe { esp_http_client_read(self.http_client, buffer.as_mut_ptr() as *mut c_char, buffer.len() as i32) };
+            if read <= 0 {
+                self.close_http_download();
+                let reason = format!("HTTP download ended at {} of {} bytes ({})",
+                    self.received_size, self.fw_size.unwrap_or(0), read);
+                self.set_state(OtaState::Failed(reason.clone()));
+                self.send_ota_telemetry(mqtt_client)?;
+                return Err(anyhow!(reason));
+            }
+            let chunk_index = self.current_chunk;
+            let result = self.handle_firmware_chunk(&buffer[..read as usize], chunk_index, mqtt_client);
+            if result.is_err() {
+                self.close_http_download();
+                return result;
+            }
+            read_this_poll += read as usize;
+        }
+        if self.ota_state == OtaState::Downloading {
+            self.http_buffer = buffer;
+        } else {
+            self.close_http_download();
+        }
+        Ok(())
+    }
+
+    fn close_http_download(&mut self) {
+        if !self.http_client.is_null() {
+            unsafe {
+                esp_http_client_close(self.http_client);
+                esp_http_client_cleanup(self.http_client);
+            }
+            self.http_client = core::ptr::null_mut();
+        }
+        self.http_buffer = Vec::new();
+    }
+
+    // Releases the OTA handle and download buffers and reports the failure, so the next update can start
+    // without a reboot. esp_ota_end frees the handle even when it fails, in which case the abort finds nothing.
+    fn abort_ota(&mut self, mqtt_client: *mut esp_mqtt_client, reason: String) -> Result<()> {
+        if self.ota_handle != 0 {
+            let res = unsafe { esp_ota_abort(self.ota_handle) };
+            if res != ESP_OK && res != ESP_ERR_NOT_FOUND as esp_err_t {
+                error!("Failed to abort OTA: {}", res);
+            }
+            self.ota_handle = 0;
+        }
+        self.chunk_buffer.clear();
+        self.partial_firmware_data.clear();
+        self.close_http_download();
+        self.set_state(OtaState::Failed(reason.clone()));
+        self.send_ota_telemetry(mqtt_client)?;
+        Err(anyhow!(reason))
+    }
+
+    fn finish_download(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        self.chunk_buffer.clear();
+        self.set_state(OtaState::Downloaded);
+        unsafe {
+            let end_start = xTaskGetTickCount();
+            let res = esp_ota_end(self.ota_handle);
+            self.flash_timings.end_ticks = ticks_elapsed(xTaskGetTickCount(), end_start).unwrap_or(u32::MAX);
+            debug!("esp_ota_end took {} ms", ticks_to_ms(self.flash_timings.end_ticks));
+            if res == ESP_ERR_OTA_VALIDATE_FAILED as esp_err_t {
+                return self.abort_ota(mqtt_client, format!("Downloaded image is invalid (esp_ota_end: {})", res));
+            } else if res != ESP_OK {
+                return self.abort_ota(mqtt_client, format!("Failed to end OTA: {}", res));
+            }
+        }
+        self.ota_handle = 0;
+        self.process_firmware(mqtt_client)
+    }
+
+    // An empty response is the terminator only once every byte is in; otherwise the server had no data for
+    // the request (wrong id, or a chunk it could not serve) and the chunk is asked for again
+    fn handle_empty_firmware_response(&mut self, request_id: u32, chunk_index: u32, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        self.partial_firmware_data.clear();
+        if self.ota_state != OtaState::Downloading {
+            info!("Ignoring empty firmware response, no download in progress");
+            return Ok(());
+        }
+        let fw_size = self.fw_size.unwrap_or(0) as usize;
+        if request_id != self.firmware_request_id {
+            error!("Empty firmware response for request {} while downloading request {}, re-requesting chunk {} with the current id",
+                request_id, self.firmware_request_id, self.current_chunk);
+            self.last_chunk_received = unsafe { xTaskGetTickCount() };
+            return self.request_firmware_chunk(mqtt_client, self.current_chunk);
+        }
+        if fw_size > 0 && self.received_size == fw_size {
+            info!("Empty terminating chunk {} received with all {} bytes present, finishing download", chunk_index, fw_size);
+            return self.finish_download(mqtt_client);
+        }
+        if chunk_index < self.current_chunk {
+            info!("Ignoring empty response for already written chunk {}", chunk_index);
+            return Ok(());
+        }
+        let chunk_count = fw_size.div_ceil(self.chunk_size) as u32;
+        let retry_chunk = if chunk_index < chunk_count {
+            error!("No data for chunk {} at {} of {} bytes, re-requesting it", chunk_index, self.received_size, fw_size);
+            chunk_index
+        } else {
+            error!("Empty response for chunk {}, past the last chunk {}, at {} of {} bytes; re-requesting chunk {}",
+                chunk_index, chunk_count.saturating_sub(1), self.received_size, fw_size, self.current_chunk);
+            self.current_chunk
+        };
+        self.last_chunk_received = unsafe { xTaskGetTickCount() };
+        self.request_firmware_chunk(mqtt_client, retry_chunk)
+    }
+
+    fn validate_firmware_fragment(&self, total_len: usize, offset: usize, fragment_len: usize) -> Result<()> {
+        let max_len = self.chunk_size + CHUNK_SIZE_MARGIN;
+        if total_len > max_len {
+            return Err(anyhow!("Firmware response advertises {} bytes, more than the {} byte limit", total_len, max_len));
+        }
+        if offset + fragment_len > total_len {
+            return Err(anyhow!("Fragment at offset {} with {} bytes overruns the advertised {} bytes", offset, fragment_len, total_len));
+        }
+        if offset != 0 && offset != self.partial_firmware_data.len() {
+            return Err(anyhow!("Fragment offset {} does not follow the {} bytes buffered so far", offset, self.partial_firmware_data.len()));
+        }
+        Ok(())
+    }
+
+    fn reject_firmware_response(&mut self, mqtt_client: *mut esp_mqtt_client, reason: &str) -> Result<()> {
+        error!("Rejecting firmware response: {}; re-requesting chunk {}", reason, self.current_chunk);
+        self.partial_firmware_data.clear();
+        self.last_chunk_received = unsafe { xTaskGetTickCount() };
+        self.request_firmware_chunk(mqtt_client, self.current_chunk)
+    }
+
+    // One MQTT data event of a firmware response; a chunk larger than the client buffer arrives in several
+    fn handle_firmware_fragment(&mut self, topic: &str, total_len: usize, offset: usize, data: &[u8], mqtt_client: *mut esp_mqtt_client) {
+        let Some((request_id, chunk_index)) = parse_chunk_topic(OTA_CHUNK_RESPONSE_TOPIC_TEMPLATE, topic) else {
+            error!("Invalid firmware response topic: {}", topic);
+            return;
+        };
+        // Empty responses are checked against the expected id and index before the stale filter,
+        // since one for the wrong id means the server never saw a valid request
+        if total_len == 0 {
+            if let Err(e) = self.handle_empty_firmware_response(request_id, chunk_index, mqtt_client) {
+                error!("Failed to handle empty firmware response: {:?}", e);
+            }
+            return;
+        }
+        if request_id != self.firmware_request_id {
+            info!("Ignoring firmware response for stale request: {}", topic);
+            return;
+        }
+        if let Err(e) = self.validate_firmware_fragment(total_len, offset, data.len()) {
+            if let Err(e) = self.reject_firmware_response(mqtt_client, &e.to_string()) {
+                error!("Failed to re-request firmware chunk: {:?}", e);
+            }
+            return;
+        }
+
+        if offset == 0 {
+            self.partial_firmware_data.clear();
+        }
+        self.partial_firmware_data.extend_from_slice(data);
+
+        if offset + data.len() >= total_len {
+            info!("Received complete firmware chunk for request ID: {}, chunk: {}, data length: {}",
+                self.firmware_request_id, chunk_index, self.partial_firmware_data.len());
+            self.last_chunk_received = unsafe { xTaskGetTickCount() };
+            let chunk_data = core::mem::take(&mut self.partial_firmware_data);
+            if let Err(e) = self.handle_firmware_chunk(&chunk_data, chunk_index, mqtt_client) {
+                error!("Failed to handle firmware chunk: {:?}", e);
+            }
+            self.partial_firmware_data.clear();
+        }
+    }
+
+    fn process_buffered_chunks(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        while let Some(index) = self.chunk_buffer.iter().position(|&(index, _)| index == self.current_chunk) {
+            let (_, data) = self.chunk_buffer.remove(index);
+            self.handle_firmware_chunk(&data, self.current_chunk, mqtt_client)?;
+        }
+        Ok(())
+    }
+
+    fn process_firmware(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        self.set_state(OtaState::Verifying);
+        self.send_ota_telemetry(mqtt_client)?;
+
+        if let Some(checksum) = &self.fw_checksum {
+            let computed_checksum = {
+                let result = match OTA_CHECKSUM_MODE {
+                    ChecksumMode::Streaming => self.sha256_hasher.clone().finalize(),
+                    ChecksumMode::Buffered => Sha256::digest(&self.image_buffer),
+                };
+                self.image_buffer = Vec::new();
+                result.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+            };
+            info!("Computed checksum: {}, Expected checksum: {}", computed_checksum, checksum);
+            if computed_checksum == *checksum {
+                if OTA_VERIFY_IMAGE_VERSION {
+                    if let Err(e) = self.verify_image_version() {
+                        error!("{}", e);
+                        self.set_state(OtaState::Failed(e.to_string()));
+                        self.send_ota_telemetry(mqtt_client)?;
+                        return Err(e);
+                    }
+                }
+                self.set_state(OtaState::Updating);
+                self.send_ota_telemetry(mqtt_client)?;
                 unsafe {
-                    self.ota_partition = esp_ota_get_next_update_partition(core::ptr::null());
-                    if self.ota_partition.is_null() {
-                        error!("esp_ota_get_next_update_partition failed. Attempting manual partition selection...");
-                        let running_partition = esp_ota_get_running_partition();
-                        if !running_partition.is_null() {
-                            let label = core::ffi::CStr::from_ptr((*running_partition).label.as_ptr()).to_str().unwrap_or("unknown");
-                            info!("Running partition: {}, address: 0x{:x}", label, (*running_partition).address);
-                        } else {
-                            error!("No running partition detected");
-                        }
+                    let res = esp_ota_set_boot_partition(self.ota_partition);
+                    if res != ESP_OK {
+                        self.set_state(OtaState::Failed(format!("Failed to set boot partition: {}", res)));
+                        self.send_ota_telemetry(mqtt_client)?;
+                        return Err(anyhow!("Failed to set boot partition: {}", res));
+                    }
+                }
+                if self.forced_update {
+                    self.record_forced_image(&computed_checksum);
+                }
+                self.current_fw_title = self.fw_title.clone().unwrap_or_default();
+                self.current_fw_version = self.fw_version.clone().unwrap_or_default();
+                self.set_state(OtaState::Updated);
+                self.send_ota_telemetry(mqtt_client)?;
+                info!("Firmware update successful, restart scheduled");
+                self.restart_pending = true;
+                Ok(())
+            } else {
+                self.set_state(OtaState::Failed("Checksum verification failed".to_string()));
+                self.send_ota_telemetry(mqtt_client)?;
+                return Err(anyhow!("Checksum verification failed"));
+            }
+        } else {
+            self.set_state(OtaState::Failed("No checksum provided".to_string()));
+            self.send_ota_telemetry(mqtt_client)?;
+            return Err(anyhow!("No checksum provided"));
+        }
+    }
+
+    fn verify_image_version(&self) -> Result<()> {
+        let mut desc = esp_app_desc_t::default();
+        let res = unsafe { esp_ota_get_partition_description(self.ota_partition, &mut desc) };
+        if res != ESP_OK {
+            return Err(anyhow!("Failed to read image descriptor: {}", res));
+        }
+        let image_version = unsafe { CStr::from_ptr(desc.version.as_ptr()) }.to_str().unwrap_or("").trim();
+        let advertised = self.fw_version.as_deref().unwrap_or("").trim();
+        // "2.0" in the image matches "V2.0" on the dashboard
+        let matches = image_version == advertised
+            || matches!((parse_version(image_version), parse_version(advertised)), (Some(a), Some(b)) if a == b);
+        if !matches {
+            return Err(anyhow!("Image version '{}' does not match advertised fw_version '{}'", image_version, advertised));
+        }
+        info!("Image version '{}' matches advertised fw_version", image_version);
+        Ok(())
+    }
+
+    fn send_ota_telemetry(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        self.telemetry_counter += 1;
+        if self.ota_state == OtaState::Downloading && self.telemetry_counter < ms_to_ticks(5000) / ms_to_ticks(100) {
+            return Ok(());
+        }
+        self.telemetry_counter = 0;
+        // Every report except FAILED identifies the running firmware
+        let mut payload = match &self.ota_state {
+            OtaState::Failed(_) => json!({}),
+            _ => self.firmware_identity_json(),
+        };
+        let state_fields = match &self.ota_state {
+            OtaState::Idle => json!({ FW_STATE_ATTR: "IDLE" }),
+            OtaState::Downloading => json!({
+                FW_STATE_ATTR: "DOWNLOADING",
+                "progress": self.progress_percent().unwrap_or(0.0),
+                "throughput_bps": self.throughput_bps().map(|bps| bps as u32),
+                "eta_seconds": self.eta_seconds(),
+                "eta_estimating": self.eta_seconds().is_none()
+            }),
+            OtaState::Downloaded => json!({ FW_STATE_ATTR: "DOWNLOADED" }),
+            OtaState::Verifying => json!({ FW_STATE_ATTR: "VERIFYING" }),
+            OtaState::Updating => json!({ FW_STATE_ATTR: "UPDATING" }),
+            OtaState::Updated => json!({
+                FW_STATE_ATTR: "UPDATED",
+                "ota_duration_seconds": self.download_duration_ms() / 1000,
+                "ota_bytes": self.received_size
+            }),
+            OtaState::Failed(error) => json!({
+                FW_STATE_ATTR: "FAILED",
+                "fw_error": error
+            }),
+        };
+        if let (Value::Object(fields), Value::Object(state_fields)) = (&mut payload, state_fields) {
+            fields.extend(state_fields);
+        }
+        // The final report of a download carries the flash timing totals
+        if self.ota_state.is_terminal() {
+            if let (Value::Object(fields), Value::Object(timings)) = (&mut payload, self.flash_timings.to_json()) {
+                fields.extend(timings);
+            }
+        }
+        let payload = payload.to_string();
+        let msg_id = Self::mqtt_publish_tracked(mqtt_client, OTA_PROGRESS_TOPIC.unwrap_or(OTA_TELEMETRY_TOPIC), payload.as_bytes(), 1, false)?;
+        if self.ota_state == OtaState::Updated {
+            self.final_report_msg_id = Some(msg_id);
+        }
+        info!("Sent OTA telemetry: {}", payload);
+        Ok(())
+    }
+
+    // The running build, as reported at boot, with OTA state, in diagnostics and in device_info
+    fn firmware_identity_json(&self) -> Value {
+        json!({
+            "current_fw_title": &self.current_fw_title,
+            "current_fw_version": &self.current_fw_version,
+            "current_fw_tag": DEFAULT_CONFIG.fw_tag,
+            "current_fw_build_timestamp": FW_BUILD_TIMESTAMP,
+            "current_fw_git_hash": FW_GIT_HASH
+        })
+    }
+
+    // From the start of the download until now
+    fn download_duration_ms(&self) -> u32 {
+        ticks_elapsed(unsafe { xTaskGetTickCount() }, self.download_started_tick).map_or(0, ticks_to_ms)
+    }
+
+    fn chunk_timeout_ms(&self) -> u32 {
+        let throughput = self.throughput_bps.filter(|bps| *bps > 0.0).unwrap_or(CHUNK_TIMEOUT_ASSUMED_BPS);
+        let transfer_ms = self.chunk_size as f32 / throughput * 1000.0 * CHUNK_TIMEOUT_MARGIN;
+        (transfer_ms as u32).clamp(CHUNK_TIMEOUT_FLOOR_MS, CHUNK_TIMEOUT_CEILING_MS)
+    }
+
+    fn check_chunk_timeout(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
+        if self.ota_state == OtaState::Downloading && self.transport == OtaTransport::Mqtt {
+            let current_ticks = unsafe { xTaskGetTickCount() };
+            let timeout_ms = self.chunk_timeout_ms();
+            match ticks_elapsed(current_ticks, self.last_chunk_received) {
+                Some(elapsed) if elapsed > ms_to_ticks(timeout_ms) => {
+                    info!("No chunks received for {} ms, re-requesting chunk {}", timeout_ms, self.current_chunk);
+                    self.request_firmware_chunk(mqtt_client, self.current_chunk)?;
+                    self.last_chunk_received = current_ticks;
+                }
+                Some(_) => {}
+                None => {
+                    error!("Last chunk time is ahead of the tick counter, restarting the chunk timer");
+                    self.last_chunk_received = current_ticks;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mqtt_publish(mqtt_client: *mut esp_mqtt_client, topic: &str, data: &str) -> Result<()> {
+        Self::mqtt_publish_bytes(mqtt_client, topic, data.as_bytes())
+    }
+
+    fn mqtt_publish_bytes(mqtt_client: *mut esp_mqtt_client, topic: &str, data: &[u8]) -> Result<()> {
+        Self::mqtt_publish_with_retain(mqtt_client, topic, data, false)
+    }
+
+    fn mqtt_publish_with_retain(mqtt_client: *mut esp_mqtt_client, topic: &str, data: &[u8], retain: bool) -> Result<()> {
+        Self::mqtt_publish_with_options(mqtt_client, topic, data, 1, retain)
+    }
+
+    fn mqtt_publish_with_options(mqtt_client: *mut esp_mqtt_client, topic: &str, data: &[u8], qos: i32, retain: bool) -> Result<()> {
+        Self::mqtt_publish_tracked(mqtt_client, topic, data, qos, retain).map(|_| ())
+    }
+
+    // Returns the message id, which a QoS 1/2 publish's MQTT_EVENT_PUBLISHED carries back
+    fn mqtt_publish_tracked(mqtt_client: *mut esp_mqtt_client, topic: &str, data: &[u8], qos: i32, retain: bool) -> Result<i32> {
+        unsafe {
+            let topic_cstr = CString::new(topic)?;
+            let msg_id = esp_mqtt_client_publish(
+                mqtt_client,
+                topic_cstr.as_ptr(),
+                data.as_ptr() as *const core::ffi::c_char,
+                data.len() as i32,
+                qos,
+                retain as i32
+            );
+            if msg_id < 0 {
+                Err(anyhow!("Failed to publish message to {}: {}", topic, msg_id))
+            } else {
+                info!("Published message to {} with ID: {}", topic, msg_id);
+                Ok(msg_id)
+            }
+        }
+    }
+}
+
+struct RpcRequest {
+    request_id: u32,
+    method: String,
+    params: Value,
+}
+
+// OTA traffic is only received on the MQTT client task and queued for the main loop, which owns the OtaManager
+enum OtaMessage {
+    SharedAttributes(String),
+    AttributeUpdate(String),
+    FirmwareFragment { topic: String, total_len: usize, offset: usize, data: Vec<u8> },
+}
+
+type TopicHandler = fn(&MqttContext, &esp_mqtt_event_t, &str, &[u8]);
+
+struct TopicRoute {
+    pattern: String,
+    handler: TopicHandler,
+    qos: i32,
+}
+
+// A SUBSCRIBE waiting for the broker's acknowledgment
+struct PendingSubscription {
+    msg_id: i32,
+    topic: String,
+    qos: i32,
+    sent_tick: u32,
+    retries: u32,
+}
+
+// State shared with the MQTT event handler, which runs on the MQTT client task. Apart from registering the routes
+// before the client starts, all methods take &self, so both tasks can hold a reference at the same time.
+struct MqttContext {
+    ota_messages: Guarded<Vec<OtaMessage>>,
+    rpc_requests: Guarded<Vec<RpcRequest>>,
+    // Message ids of the latest acknowledged QoS 1/2 publishes
+    published_acks: Guarded<Vec<i32>>,
+    routes: Vec<TopicRoute>,
+    connected: AtomicBool,
+    ever_connected: AtomicBool,
+    // Raised by every MQTT_EVENT_CONNECTED after the first, until the main loop restores the subscriptions
+    reconnected: AtomicBool,
+    // Subscribe requests the broker has not acknowledged yet
+    pending_subscriptions: AtomicU32,
+    // Those requests, to check the QoS the broker grants and to resend the ones it never acknowledges
+    requested_subscriptions: Guarded<Vec<PendingSubscription>>,
+    // Raised by every MQTT_EVENT_CONNECTED until the main loop has published the device info
+    device_info_pending: AtomicBool,
+}
+
+impl MqttContext {
+    fn new() -> Self {
+        Self {
+            ota_messages: Guarded::new(Vec::new()),
+            rpc_requests: Guarded::new(Vec::new()),
+            published_acks: Guarded::new(Vec::new()),
+            routes: Vec::new(),
+            connected: AtomicBool::new(false),
+            ever_connected: AtomicBool::new(false),
+            reconnected: AtomicBool::new(false),
+            pending_subscriptions: AtomicU32::new(0),
+            requested_subscriptions: Guarded::new(Vec::new()),
+            device_info_pending: AtomicBool::new(false),
+        }
+    }
+
+    // Set by MQTT_EVENT_CONNECTED / MQTT_EVENT_DISCONNECTED (and a client restart), so publishing can wait for a live session
+    fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Release);
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    fn on_connected(&self) {
+        if self.ever_connected.swap(true, Ordering::AcqRel) {
+            self.reconnected.store(true, Ordering::Release);
+        }
+        self.device_info_pending.store(true, Ordering::Release);
+        self.set_connected(true);
+    }
+
+    fn take_reconnected(&self) -> bool {
+        self.reconnected.swap(false, Ordering::AcqRel)
+    }
+
+    fn take_device_info_pending(&self) -> bool {
+        self.device_info_pending.swap(false, Ordering::AcqRel)
+    }
+
+    // `granted` is the SUBACK return code: the granted QoS, or 0x80 when the broker refused the subscription
+    // An acknowledgment that arrives after its request was resent matches no pending entry and only gets logged;
+    // the resent request is still counted until its own acknowledgment
+    fn on_subscribed(&self, msg_id: i32, granted: Option<u8>) {
+        let request = self.requested_subscriptions.with(|requests| {
+            requests.iter().position(|request| request.msg_id == msg_id).map(|index| requests.swap_remove(index))
+        });
+        let Some(request) = request else {
+            info!("Subscription {} acknowledged", msg_id);
+            return;
+        };
+        let _ = self.pending_subscriptions.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        match granted {
+            Some(0x80) => error!("Broker refused subscription to {}", request.topic),
+            Some(granted) if (granted as i32) < request.qos => {
+                warn!("Subscribed to {} at QoS {}, downgraded from the requested QoS {}", request.topic, granted, request.qos);
+            }
+            Some(granted) => info!("Subscribed to {} at QoS {}", request.topic, granted),
+            None => info!("Subscribed to {}, granted QoS not reported", request.topic),
+        }
+    }
+
+    // Resends subscriptions the broker has not acknowledged within SUBSCRIBE_ACK_TIMEOUT_MS. Returns the topics
+    // given up on after SUBSCRIBE_ACK_RETRIES resends, which no longer hold back subscriptions_confirmed.
+    fn retry_unacknowledged_subscriptions(&self, mqtt_client: &SimpleMqttClient) -> Vec<String> {
+        let now = unsafe { xTaskGetTickCount() };
+        let timeout_ticks = ms_to_ticks(SUBSCRIBE_ACK_TIMEOUT_MS);
+        let timed_out: Vec<PendingSubscription> = self.requested_subscriptions.with(|requests| {
+            let (timed_out, waiting) = core::mem::take(requests).into_iter()
+                .partition(|request| ticks_elapsed(now, request.sent_tick).is_some_and(|elapsed| elapsed >= timeout_ticks));
+            *requests = waiting;
+            timed_out
+        });
+        let mut abandoned = Vec::new();
+        for request in timed_out {
+            if request.retries >= SUBSCRIBE_ACK_RETRIES {
+                error!("Subscription to {} never acknowledged after {} resends, giving up", request.topic, request.retries);
+                let _ = self.pending_subscriptions.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+                abandoned.push(request.topic);
+                continue;
+            }
+            warn!("Subscription to {} (msg {}) not acknowledged within {} ms, resending",
+                request.topic, request.msg_id, SUBSCRIBE_ACK_TIMEOUT_MS);
+            match mqtt_client.subscribe(&request.topic, request.qos) {
+                Ok(msg_id) => {
+                    let sent_tick = unsafe { xTaskGetTickCount() };
+                    let retry = PendingSubscription { msg_id, sent_tick, retries: request.retries + 1, ..request };
+                    self.requested_subscriptions.with(|requests| requests.push(retry));
+                }
+                Err(e) => {
+                    error!("Failed to resend subscription to {}: {:?}", request.topic, e);
+                    let _ = self.pending_subscriptions.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+                    abandoned.push(request.topic);
+                }
+            }
+        }
+        abandoned
+    }
+
+    fn subscriptions_confirmed(&self) -> bool {
+        self.pending_subscriptions.load(Ordering::Acquire) == 0
+    }
+
+    // Every routed topic is also a subscription
+    fn subscribe_all(&self, mqtt_client: &SimpleMqttClient) {
+        self.requested_subscriptions.with(|requests| requests.clear());
+        for route in &self.routes {
+            match mqtt_client.subscribe(&route.pattern, route.qos) {
+                Ok(msg_id) => {
+                    self.pending_subscriptions.fetch_add(1, Ordering::AcqRel);
+                    let request = PendingSubscription {
+                        msg_id,
+                        topic: route.pattern.clone(),
+                        qos: route.qos,
+                        sent_tick: unsafe { xTaskGetTickCount() },
+                        retries: 0,
+                    };
+                    self.requested_subscriptions.with(|requests| requests.push(request));
+                }
+                Err(e) => error!("Failed to subscribe to {}: {:?}", route.pattern, e),
+            }
+        }
+    }
+
+    // Routes must be registered before the MQTT client is started
+    fn register_topic_handler(&mut self, pattern: &str, handler: TopicHandler, qos: i32) {
+        self.routes.push(TopicRoute { pattern: pattern.to_string(), handler, qos });
+    }
+
+    fn dispatch(&self, event: &esp_mqtt_event_t, topic: &str, data: &[u8]) {
+        let handler = self.routes.iter().find(|route| topic_matches(&route.pattern, topic)).map(|route| route.handler);
+        match handler {
+            Some(handler) => handler(self, event, topic, data),
+            None => match UNKNOWN_TOPIC_POLICY {
+                UnknownTopicPolicy::Log => info!("Received MQTT message on unexpected topic: {}", topic),
+                UnknownTopicPolicy::Ignore => {}
+                UnknownTopicPolicy::Report => {
+                    let payload = json!({ "unexpected_topic": topic }).to_string();
+                    if let Err(e) = OtaManager::mqtt_publish(event.client, OTA_TELEMETRY_TOPIC, &payload) {
+                        error!("Failed to report unexpected topic: {:?}", e);
+                    }
+                }
+            },
+        }
+    }
+
+    fn push_ota_message(&self, message: OtaMessage) {
+        self.ota_messages.with(|messages| messages.push(message));
+    }
+
+    fn take_ota_messages(&self) -> Vec<OtaMessage> {
+        self.ota_messages.with(core::mem::take)
+    }
+
+    fn push_rpc_request(&self, request: RpcRequest) {
+        self.rpc_requests.with(|requests| requests.push(request));
+    }
+
+    fn take_rpc_requests(&self) -> Vec<RpcRequest> {
+        self.rpc_requests.with(core::mem::take)
+    }
+
+    fn on_published(&self, msg_id: i32) {
+        self.published_acks.with(|acks| {
+            if acks.len() >= 16 {
+                acks.remove(0);
+            }
+            acks.push(msg_id);
+        });
+    }
+
+    // Polls until the broker has acknowledged `msg_id`; false on timeout
+    fn wait_for_publish_ack(&self, msg_id: i32, timeout_ms: u32) -> bool {
+        let mut waited_ms = 0;
+        loop {
+            if self.published_acks.with(|acks| acks.contains(&msg_id)) {
+                return true;
+            }
+            if waited_ms >= timeout_ms {
+                return false;
+            }
+            unsafe { vTaskDelay(ms_to_ticks(50)); }
+            waited_ms += 50;
+        }
+    }
+}
+
+fn validate_chunk_topic_template(template: &str) -> Result<()> {
+    for placeholder in ["{id}", "{index}"] {
+        if !template.split('/').any(|level| level == placeholder) {
+            return Err(anyhow!("Chunk topic template '{}' needs {} as a whole topic level", template, placeholder));
+        }
+    }
+    if template.split('/').any(|level| level == "+" || level == "#") {
+        return Err(anyhow!("Chunk topic template '{}' must not contain MQTT wildcards", template));
+    }
+    Ok(())
+}
+
+fn render_chunk_topic(template: &str, request_id: u32, chunk_index: u32) -> String {
+    template.replace("{id}", &request_id.to_string()).replace("{index}", &chunk_index.to_string())
+}
+
+fn chunk_topic_subscription(template: &str) -> String {
+    template.replace("{id}", "+").replace("{index}", "+")
+}
+
+// Extracts (request id, chunk index) from a topic matching the template
+fn parse_chunk_topic(template: &str, topic: &str) -> Option<(u32, u32)> {
+    let mut template_levels = template.split('/');
+    let mut topic_levels = topic.split('/');
+    let (mut request_id, mut chunk_index) = (None, None);
+    loop {
+        match (template_levels.next(), topic_levels.next()) {
+            (Some("{id}"), Some(level)) => request_id = Some(level.parse().ok()?),
+            (Some("{index}"), Some(level)) => chunk_index = Some(level.parse().ok()?),
+            (Some(expected), Some(level)) if expected == level => {}
+            (None, None) => return Some((request_id?, chunk_index?)),
+            _ => return None,
+        }
+    }
+}
+
+fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern_levels = pattern.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (pattern_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(expected), Some(level)) if expected == level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn on_attribute_response(context: &MqttContext, _event: &esp_mqtt_event_t, _topic: &str, data: &[u8]) {
+    if let Ok(data_str) = core::str::from_utf8(data) {
+        context.push_ota_message(OtaMessage::SharedAttributes(data_str.to_string()));
+    } else {
+        error!("Invalid UTF-8 in OTA response");
+    }
+}
+
+fn on_attribute_update(context: &MqttContext, _event: &esp_mqtt_event_t, _topic: &str, data: &[u8]) {
+    if let Ok(data_str) = core::str::from_utf8(data) {
+        context.push_ota_message(OtaMessage::AttributeUpdate(data_str.to_string()));
+    } else {
+        error!("Invalid UTF-8 in attribute update");
+    }
+}
+
+fn on_firmware_response(context: &MqttContext, event: &esp_mqtt_event_t, topic: &str, data: &[u8]) {
+    context.push_ota_message(OtaMessage::FirmwareFragment {
+        topic: topic.to_string(),
+        total_len: event.total_data_len as usize,
+        offset: event.current_data_offset as usize,
+        data: data.to_vec(),
+    });
+}
+
+fn on_rpc_request(context: &MqttContext, _event: &esp_mqtt_event_t, topic: &str, data: &[u8]) {
+    let request_id = topic.strip_prefix(RPC_REQUEST_TOPIC).unwrap_or("");
+    match (request_id.parse::<u32>(), serde_json::from_slice::<Value>(data)) {
+        (Ok(request_id), Ok(body)) => {
+            let method = body.get("method").and_then(|m| m.as_str()).unwrap_or("").to_string();
+            // Flat bodies such as {"method":"boostTelemetry","seconds":60} carry their params inline
+            let params = body.get("params").cloned().unwrap_or_else(|| body.clone());
+            info!("RPC request {} received: {}", request_id, method);
+            context.push_rpc_request(RpcRequest { request_id, method, params });
+        }
+        _ => error!("Invalid RPC request on topic: {}", topic),
+    }
+}
+
+struct SimpleMqttClient {
+    client: *mut esp_mqtt_client,
+    context: *const MqttContext,
+}
+
+impl SimpleMqttClient {
+    fn new(broker_url: &str, username: &str, password: &str, client_id: &str, context_ptr: *const MqttContext) -> Result<Self> {
+        unsafe {
+            let broker_url_cstr = CString::new(broker_url)?;
+            let username_cstr = CString::new(username)?;
+            let password_cstr = CString::new(password)?;
+            let client_id_cstr = CString::new(client_id)?;
+            let config = esp_mqtt_client_config_t {
+                broker: esp_mqtt_client_config_t_broker_t {
+                    address: esp_mqtt_client_config_t_broker_t_address_t {
+                        uri: broker_url_cstr.as_ptr(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                credentials: esp_mqtt_client_config_t_credentials_t {
+                    username: username_cstr.as_ptr(),
+                    client_id: client_id_cstr.as_ptr(),
+                    authentication: esp_mqtt_client_config_t_credentials_t_authentication_t {
+                        password: password_cstr.as_ptr(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                buffer: esp_mqtt_client_config_t_buffer_t {
+                    size: MQTT_BUFFER_SIZE,
+                    out_size: MQTT_BUFFER_SIZE,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let client = esp_mqtt_client_init(&config);
+            if client.is_null() {
+                return Err(anyhow!("Failed to initialize MQTT client"));
+            }
+            esp_mqtt_client_register_event(
+                client,
+                esp_mqtt_event_id_t_MQTT_EVENT_ANY,
+                Some(Self::mqtt_event_handler),
+                context_ptr as *mut c_void
+            );
+            let err = esp_mqtt_client_start(client);
+            if err != ESP_OK {
+                esp_mqtt_client_destroy(client);
+                return Err(anyhow!("Failed to start MQTT client, error code: {}", err));
+            }
+            vTaskDelay(ms_to_ticks(5000));
+            info!("MQTT client started, verifying subscriptions...");
+            Ok(Self { client, context: context_ptr })
+        }
+    }
+
+    // Slices an event's topic or data buffer; None if the length is negative, larger than the client buffer,
+    // or nonzero with a null pointer
+    unsafe fn event_slice<'a>(ptr: *const c_char, len: i32) -> Option<&'a [u8]> {
+        if len == 0 {
+            return Some(&[]);
+        }
+        if !(0..=MQTT_BUFFER_SIZE).contains(&len) || ptr.is_null() {
+            return None;
+        }
+        Some(core::slice::from_raw_parts(ptr as *const u8, len as usize))
+    }
+
+    extern "C" fn mqtt_event_handler(
+        handler_args: *mut c_void,
+        _base: *const u8,
+        event_id: i32,
+        event_data: *mut c_void
+    ) {
+        unsafe {
+            let context = handler_args as *const MqttContext;
+            if context.is_null() {
+                error!("MQTT context pointer is null");
+                return;
+            }
+            let context = &*context;
+            if event_data.is_null() {
+                error!("MQTT event data pointer is null");
+                return;
+            }
+            let event = &*(event_data as *mut esp_mqtt_event_t);
+            info!("MQTT event received, event_id: {}", event_id);
+            match event_id {
+                id if id == esp_mqtt_event_id_t_MQTT_EVENT_CONNECTED as i32 => {
+                    info!("MQTT connected to broker");
+                    context.on_connected();
+                }
+                id if id == esp_mqtt_event_id_t_MQTT_EVENT_DISCONNECTED as i32 => {
+                    error!("MQTT disconnected from broker");
+                    context.set_connected(false);
+                }
+                id if id == esp_mqtt_event_id_t_MQTT_EVENT_SUBSCRIBED as i32 => {
+                    // The event data carries the SUBACK return codes, one per topic; subscriptions are single-topic
+                    let granted = Self::event_slice(event.data, event.data_len).and_then(|codes| codes.first().copied());
+                    context.on_subscribed(event.msg_id, granted);
+                }
+                id if id == esp_mqtt_event_id_t_MQTT_EVENT_PUBLISHED as i32 => {
+                    context.on_published(event.msg_id);
+                }
+                id if id == esp_mqtt_event_id_t_MQTT_EVENT_DATA as i32 => {
+                    let (Some(topic_slice), Some(data_slice)) = (
+                        Self::event_slice(event.topic, event.topic_len),
+                        Self::event_slice(event.data, event.data_len),
+                    ) else {
+                        error!("Dropping malformed MQTT data event: topic_len {}, data_len {}", event.topic_len, event.data_len);
+                        return;
+                    };
+                    if !topic_slice.is_empty() {
+                        let topic = core::str::from_utf8(topic_slice).unwrap_or("");
+                        info!("Received MQTT message on topic: {}, data_len: {}", topic, data_slice.len());
+                        context.dispatch(event, topic, data_slice);
+                    }
+                }
+                _ => {
+                    info!("Unhandled MQTT event, event_id: {}", event_id);
+                }
+            }
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        unsafe { (*self.context).is_connected() }
+    }
+
+    fn publish(&self, topic: &str, data: &str) -> Result<()> {
+        OtaManager::mqtt_publish(self.client, topic, data)
+    }
+
+    fn publish_retained(&self, topic: &str, data: &str) -> Result<()> {
+        OtaManager::mqtt_publish_with_retain(self.client, topic, data.as_bytes(), true)
+    }
+
+    fn publish_with_qos(&self, topic: &str, data: &[u8], qos: i32) -> Result<()> {
+        OtaManager::mqtt_publish_with_options(self.client, topic, data, qos, false)
+    }
+
+    fn reconnect(&self) -> Result<()> {
+        if MQTT_RESTART_CLIENT_ON_RECONNECT {
+            return self.restart();
+        }
+        let res = unsafe { esp_mqtt_client_reconnect(self.client) };
+        if res != ESP_OK {
+            return Err(anyhow!("Failed to reconnect MQTT client, error code: {}", res));
+        }
+        Ok(())
+    }
+
+    // Stops the client task and starts a fresh connection on the same handle. The event handler stays registered
+    // with the handle and its context is leaked at startup, so the pointer remains valid; the CONNECTED event that
+    // follows flags a reconnect and the main loop re-subscribes from there.
+    fn restart(&self) -> Result<()> {
+        info!("Restarting MQTT client");
+        let res = unsafe { esp_mqtt_client_stop(self.client) };
+        if res != ESP_OK {
+            warn!("Failed to stop MQTT client, error code: {}", res);
+        }
+        // Stopping does not dispatch a DISCONNECTED event
+        unsafe { (*self.context).set_connected(false) };
+        let res = unsafe { esp_mqtt_client_start(self.client) };
+        if res != ESP_OK {
+            return Err(anyhow!("Failed to restart MQTT client, error code: {}", res));
+        }
+        Ok(())
+    }
+
+    // Returns the message id of the SUBSCRIBE, which the broker's acknowledgment carries back
+    fn subscribe(&self, topic: &str, qos: i32) -> Result<i32> {
+        unsafe {
+            let topic_cstr = CString::new(topic)?;
+            let result = esp_mqtt_client_subscribe_single(
+                self.client,
+                topic_cstr.as_ptr(),
+                qos
+            );
+            if result == -1 {
+                error!("Failed to subscribe to topic: {}, retrying...", topic);
+                vTaskDelay(ms_to_ticks(1000));
+                let retry_result = esp_mqtt_client_subscribe_single(
+                    self.client,
+                    topic_cstr.as_ptr(),
+                    qos
+                );
+                if retry_result == -1 {
+                    Err(anyhow!("Failed to subscribe to topic after retry: {}", topic))
+                } else {
+                    info!("Subscribe to {} at QoS {} sent after retry", topic, qos);
+                    Ok(retry_result)
+                }
+            } else {
+                info!("Subscribe to {} at QoS {} sent", topic, qos);
+                Ok(result)
+            }
+        }
+    }
+}
+
+impl Drop for SimpleMqttClient {
+    fn drop(&mut self) {
+        unsafe {
+            esp_mqtt_client_stop(self.client);
+            esp_mqtt_client_destroy(self.client);
+        }
+    }
+}
+
+struct TelemetryBatch {
+    entries: Vec<Value>,
+    first_entry_tick: u32,
+}
+
+impl TelemetryBatch {
+    fn new() -> Self {
+        Self {
+            entries: Vec::with_capacity(TELEMETRY_BATCH_SIZE),
+            first_entry_tick: 0,
+        }
+    }
+
+    fn push(&mut self, entry: Value) {
+        if self.entries.is_empty() {
+            self.first_entry_tick = unsafe { xTaskGetTickCount() };
+        }
+        // Keep at most two batches around if the broker is unreachable
+        if self.entries.len() >= TELEMETRY_BATCH_SIZE * 2 {
+            self.entries.remove(0);
+        }
+        self.entries.push(entry);
+    }
+
+    fn is_due(&self) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        let elapsed = ticks_elapsed(unsafe { xTaskGetTickCount() }, self.first_entry_tick).unwrap_or(u32::MAX);
+        self.entries.len() >= TELEMETRY_BATCH_SIZE || elapsed >= ms_to_ticks(TELEMETRY_BATCH_FLUSH_INTERVAL_MS)
+    }
+
+    fn flush(&mut self, mqtt_client: &SimpleMqttClient) -> Result<()> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        publish_telemetry(mqtt_client, &Value::Array(self.entries.clone()))?;
+        info!("Telemetry batch of {} readings sent to ThingsBoard", self.entries.len());
+        self.entries.clear();
+        Ok(())
+    }
+}
+
+// Readings taken before the first broker connection, keyed by uptime so they can be back-dated once SNTP syncs
+struct BootBacklog {
+    entries: Vec<(i64, Value)>,
+    first_flush_tick: Option<u32>,
+    dropped_by_age: u32,
+    dropped_by_capacity: u32,
+    dropped_by_memory: u32,
+    // Buffering is suspended while free heap is below BOOT_BACKLOG_MIN_FREE_HEAP_BYTES
+    suspended: bool,
+}
+
+impl BootBacklog {
+    fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            first_flush_tick: None,
+            dropped_by_age: 0,
+            dropped_by_capacity: 0,
+            dropped_by_memory: 0,
+            suspended: false,
+        }
+    }
+
+    fn push(&mut self, values: Value) {
+        let uptime_ms = unsafe { esp_timer_get_time() } / 1000;
+        self.evict_stale(uptime_ms);
+        let free_heap = unsafe { esp_get_free_heap_size() };
+        let low_memory = free_heap < BOOT_BACKLOG_MIN_FREE_HEAP_BYTES;
+        if low_memory != self.suspended {
+            if low_memory {
+                error!("Free heap down to {} bytes, suspending the reading backlog at {} entries", free_heap, self.entries.len());
+            } else {
+                info!("Free heap back to {} bytes, resuming the reading backlog", free_heap);
+            }
+            self.suspended = low_memory;
+        }
+        if self.suspended {
+            self.dropped_by_memory += 1;
+            return;
+        }
+        if self.entries.len() >= BOOT_BACKLOG_CAPACITY {
+            self.entries.remove(0);
+            self.dropped_by_capacity += 1;
+        }
+        self.entries.push((uptime_ms, values));
+    }
+
+    // Entries are in the order they were taken, so the stale ones are all at the front
+    fn evict_stale(&mut self, uptime_ms: i64) {
+        let stale = self.entries.iter()
+            .take_while(|(taken_at, _)| uptime_ms - taken_at > BOOT_BACKLOG_MAX_AGE_MS as i64)
+            .count();
+        if stale > 0 {
+            self.entries.drain(..stale);
+            self.dropped_by_age += stale as u32;
+        }
+    }
+
+    // Drop counts since the last report and whether buffering is suspended, as telemetry keys; None when nothing
+    // was dropped
+    fn take_drop_report(&mut self) -> Option<Value> {
+        if self.dropped_by_age == 0 && self.dropped_by_capacity == 0 && self.dropped_by_memory == 0 {
+            return None;
+        }
+        let report = json!({
+            "backlog_dropped_age": self.dropped_by_age,
+            "backlog_dropped_capacity": self.dropped_by_capacity,
+            "backlog_dropped_memory": self.dropped_by_memory,
+            "backlog_suspended": self.suspended
+        });
+        self.dropped_by_age = 0;
+        self.dropped_by_capacity = 0;
+        self.dropped_by_memory = 0;
+        Some(report)
+    }
+
+    fn flush(&mut self, mqtt_client: &SimpleMqttClient, time_sync: &TimeSync) -> Result<()> {
+        self.evict_stale(unsafe { esp_timer_get_time() } / 1000);
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        let now = unsafe { xTaskGetTickCount() };
+        let first_flush_tick = *self.first_flush_tick.get_or_insert(now);
+        let reliable = time_sync.is_reliable();
+        if !reliable && ticks_elapsed(now, first_flush_tick).unwrap_or(u32::MAX) < ms_to_ticks(BOOT_BACKLOG_TIME_WAIT_MS) {
+            return Ok(());
+        }
+        if !reliable {
+            error!("SNTP not synced, publishing {} held readings without their original timestamps", self.entries.len());
+        }
+        let now_ms = current_timestamp_ms() as i64;
+        let uptime_ms = unsafe { esp_timer_get_time() } / 1000;
+        // Published entries leave the backlog chunk by chunk, so a failed publish only retries what is left
+        while !self.entries.is_empty() {
+            let count = self.entries.len().min(BOOT_BACKLOG_FLUSH_CHUNK);
+            let range = match BOOT_BACKLOG_FLUSH_ORDER {
+                BacklogFlushOrder::OldestFirst => 0..count,
+                BacklogFlushOrder::NewestFirst => self.entries.len() - count..self.entries.len(),
+            };
+            let mut payload: Vec<Value> = self.entries[range.clone()].iter()
+                .map(|(taken_at, values)| if reliable {
+                    json!({ "ts": now_ms - (uptime_ms - taken_at), "values": values })
+                } else {
+                    values.clone()
+                })
+                .collect();
+            if let BacklogFlushOrder::NewestFirst = BOOT_BACKLOG_FLUSH_ORDER {
+                payload.reverse();
+            }
+            publish_telemetry(mqtt_client, &Value::Array(payload))?;
+            self.entries.drain(range);
+            info!("Published {} readings held while connecting, {} left", count, self.entries.len());
+        }
+        Ok(())
+    }
+}
+
+struct TelemetryChangeFilter {
+    last_published: Option<serde_json::Map<String, Value>>,
+    last_publish_tick: u32,
+}
+
+impl TelemetryChangeFilter {
+    fn new() -> Self {
+        Self { last_published: None, last_publish_tick: 0 }
+    }
+
+    fn change_delta(key: &str) -> f64 {
+        match key {
+            "temperature" => TEMPERATURE_CHANGE_DELTA,
+            "humidity" => HUMIDITY_CHANGE_DELTA,
+            "pressure" => PRESSURE_CHANGE_DELTA,
+            "co2_ppm" => CO2_CHANGE_DELTA,
+            _ => DEFAULT_CHANGE_DELTA,
+        }
+    }
+
+    // Returns true and remembers the values when they should be published
+    fn should_publish(&mut self, values: &Value) -> bool {
+        let Value::Object(current) = values else {
+            return true;
+        };
+        let now = unsafe { xTaskGetTickCount() };
+        let changed = match &self.last_published {
+            None => true,
+            Some(_) if ticks_elapsed(now, self.last_publish_tick).unwrap_or(u32::MAX) >= ms_to_ticks(PUBLISH_MAX_SILENCE_MS) => true,
+            Some(previous) => previous.len() != current.len() || current.iter().any(|(key, value)| {
+                match (previous.get(key), value.as_f64()) {
+                    (Some(last), Some(value)) => match last.as_f64() {
+                        Some(last) => (value - last).abs() > Self::change_delta(key),
+                        None => true,
+                    },
+                    (Some(last), None) => last != value,
+                    (None, _) => true,
+                }
+            }),
+        };
+        if changed {
+            self.last_published = Some(current.clone());
+            self.last_publish_tick = now;
+        }
+        changed
+    }
+}
+
+impl AlarmMetric {
+    fn key(self) -> &'static str {
+        match self {
+            AlarmMetric::Temperature => "temperature",
+            AlarmMetric::Humidity => "humidity",
+            AlarmMetric::Pressure => "pressure",
+            AlarmMetric::Co2Ppm => "co2_ppm",
+            AlarmMetric::ChipTemperature => "chip_temperature",
+        }
+    }
+
+    // Same units as the telemetry (pressure in hPa); None while the sensor has nothing to report
+    fn value(self, reading: &ReadingSnapshot) -> Option<f32> {
+        match self {
+            AlarmMetric::Temperature => Some(reading.temperature),
+            AlarmMetric::Humidity => Some(reading.humidity),
+            AlarmMetric::Pressure => Some(reading.pressure / 100.0),
+            AlarmMetric::Co2Ppm if co2_warming_up() => None,
+            AlarmMetric::Co2Ppm => reading.co2_ppm,
+            AlarmMetric::ChipTemperature => reading.chip_temperature,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum AlarmLevel {
+    Normal,
+    Warning,
+    Critical,
+}
+
+impl AlarmLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            AlarmLevel::Normal => "NORMAL",
+            AlarmLevel::Warning => "WARNING",
+            AlarmLevel::Critical => "CRITICAL",
+        }
+    }
+}
+
+struct AlarmRule {
+    metric: AlarmMetric,
+    direction: AlarmDirection,
+    warning: f32,
+    critical: f32,
+    hysteresis: f32,
+}
+
+impl AlarmRule {
+    // True when the value is beyond the threshold, or still within `margin` of it on the safe side
+    fn exceeds(&self, value: f32, threshold: f32, margin: f32) -> bool {
+        match self.direction {
+            AlarmDirection::Above => value >= threshold - margin,
+            AlarmDirection::Below => value <= threshold + margin,
+        }
+    }
+
+    fn breached_level(&self, value: f32) -> AlarmLevel {
+        if self.exceeds(value, self.critical, 0.0) {
+            AlarmLevel::Critical
+        } else if self.exceeds(value, self.warning, 0.0) {
+            AlarmLevel::Warning
+        } else {
+            AlarmLevel::Normal
+        }
+    }
+
+    // The level an active alarm steps down to; it holds until the value clears the threshold by the hysteresis
+    fn settled_level(&self, value: f32, level: AlarmLevel) -> AlarmLevel {
+        let mut settled = level;
+        if settled == AlarmLevel::Critical && !self.exceeds(value, self.critical, self.hysteresis) {
+            settled = AlarmLevel::Warning;
+        }
+        if settled == AlarmLevel::Warning && !self.exceeds(value, self.warning, self.hysteresis) {
+            settled = AlarmLevel::Normal;
+        }
+        settled
+    }
+}
+
+struct AlarmChange {
+    rule: &'static AlarmRule,
+    from: AlarmLevel,
+    to: AlarmLevel,
+    value: f32,
+}
+
+impl AlarmChange {
+    fn is_critical(&self) -> bool {
+        self.from == AlarmLevel::Critical || self.to == AlarmLevel::Critical
+    }
+
+    fn to_json(&self) -> Value {
+        let key = self.rule.metric.key();
+        json!({
+            format!("alarm_{}", key): self.to.as_str(),
+            format!("alarm_{}_value", key): self.value,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct AlarmState {
+    level: AlarmLevel,
+    // Consecutive readings above the current level, and the lowest level they all reached
+    breaches: u32,
+    breach_level: AlarmLevel,
+}
+
+struct AlarmMonitor {
+    states: Vec<AlarmState>,
+}
+
+impl AlarmMonitor {
+    fn new() -> Self {
+        let idle = AlarmState { level: AlarmLevel::Normal, breaches: 0, breach_level: AlarmLevel::Normal };
+        Self { states: alloc::vec![idle; ALARM_RULES.len()] }
+    }
+
+    // Feeds one reading through every rule and returns the alarms that fired, escalated or cleared
+    fn update(&mut self, reading: &ReadingSnapshot) -> Vec<AlarmChange> {
+        let mut changes = Vec::new();
+        for (rule, state) in ALARM_RULES.iter().zip(self.states.iter_mut()) {
+            let Some(value) = rule.metric.value(reading) else {
+                state.breaches = 0;
+                continue;
+            };
+            let breached = rule.breached_level(value);
+            let level = if breached > state.level {
+                state.breach_level = if state.breaches == 0 || breached < state.breach_level { breached } else { state.breach_level };
+                state.breaches += 1;
+                if state.breaches >= ALARM_DEBOUNCE_SAMPLES {
+                    state.breach_level
+                } else {
+                    state.level
+                }
+            } else {
+                rule.settled_level(value, state.level)
+            };
+            if breached <= state.level || level != state.level {
+                state.breaches = 0;
+            }
+            if level != state.level {
+                changes.push(AlarmChange { rule, from: state.level, to: level, value });
+                state.level = level;
+            }
+        }
+        changes
+    }
+}
+
+fn publish_alarm(mqtt_client: &SimpleMqttClient, change: &AlarmChange) -> Result<()> {
+    let (topic, qos) = if change.is_critical() {
+        (ALARM_CRITICAL_TOPIC, ALARM_CRITICAL_QOS)
+    } else {
+        (ALARM_WARNING_TOPIC, ALARM_WARNING_QOS)
+    };
+    mqtt_client.publish_with_qos(topic, change.to_json().to_string().as_bytes(), qos)
+}
+
+#[derive(Clone, Copy)]
+enum LifetimeCounter {
+    TelemetryPublished,
+    OtaSuccesses,
+    OtaFailures,
+    Reboots,
+    WifiReconnects,
+}
+
+// Consecutive successful telemetry publishes since the last failed one
+static TELEMETRY_PUBLISH_STREAK: AtomicU32 = AtomicU32::new(0);
+// Consecutive failed telemetry publishes since the last successful one
+static TELEMETRY_PUBLISH_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+// Lifetime totals, loaded from NVS at boot and incremented from wherever the event happens
+static LIFETIME_COUNTS: [AtomicU32; 5] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+impl LifetimeCounter {
+    const ALL: [LifetimeCounter; 5] = [
+        LifetimeCounter::TelemetryPublished,
+        LifetimeCounter::OtaSuccesses,
+        LifetimeCounter::OtaFailures,
+        LifetimeCounter::Reboots,
+        LifetimeCounter::WifiReconnects,
+    ];
+
+    // Doubles as the NVS key, which is limited to 15 characters
+    fn name(self) -> &'static str {
+        match self {
+            LifetimeCounter::TelemetryPublished => "telemetry_sent",
+            LifetimeCounter::OtaSuccesses => "ota_successes",
+            LifetimeCounter::OtaFailures => "ota_failures",
+            LifetimeCounter::Reboots => "reboots",
+            LifetimeCounter::WifiReconnects => "wifi_reconnects",
+        }
+    }
+
+    fn increment(self) {
+        LIFETIME_COUNTS[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(self) -> u32 {
+        LIFETIME_COUNTS[self as usize].load(Ordering::Relaxed)
+    }
+
+    // Returns the count that was cleared
+    fn reset(self) -> u32 {
+        LIFETIME_COUNTS[self as usize].swap(0, Ordering::Relaxed)
+    }
+}
+
+fn lifetime_stats_json() -> Value {
+    let mut stats = serde_json::Map::new();
+    for counter in LifetimeCounter::ALL {
+        stats.insert(format!("lifetime_{}", counter.name()), json!(counter.get()));
+    }
+    Value::Object(stats)
+}
+
+struct LifetimeStats {
+    nvs: Option<EspDefaultNvs>,
+    persisted: [u32; 5],
+    last_flush_tick: u32,
+}
+
+impl LifetimeStats {
+    // Loads the stored totals and counts this boot
+    fn open(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = match EspNvs::new(partition, STATS_NVS_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                error!("Failed to open stats NVS namespace, lifetime counters start from zero: {:?}", e);
+                None
+            }
+        };
+        let mut persisted = [0u32; 5];
+        if let Some(nvs) = nvs.as_ref() {
+            for counter in LifetimeCounter::ALL {
+                persisted[counter as usize] = nvs.get_u32(counter.name()).ok().flatten().unwrap_or(0);
+                LIFETIME_COUNTS[counter as usize].store(persisted[counter as usize], Ordering::Relaxed);
+            }
+        }
+        let mut stats = Self { nvs, persisted, last_flush_tick: unsafe { xTaskGetTickCount() } };
+        LifetimeCounter::Reboots.increment();
+        stats.flush();
+        stats
+    }
+
+    fn flush_due(&self) -> bool {
+        let now = unsafe { xTaskGetTickCount() };
+        ticks_elapsed(now, self.last_flush_tick).unwrap_or(u32::MAX) >= ms_to_ticks(STATS_FLUSH_INTERVAL_MS)
+    }
+
+    // Writes only the counters that changed since the last flush
+    fn flush(&mut self) {
+        self.last_flush_tick = unsafe { xTaskGetTickCount() };
+        let Some(nvs) = self.nvs.as_ref() else {
+            return;
+        };
+        for counter in LifetimeCounter::ALL {
+            let value = counter.get();
+            if value == self.persisted[counter as usize] {
+                continue;
+            }
+            match nvs.set_u32(counter.name(), value) {
+                Ok(()) => self.persisted[counter as usize] = value,
+                Err(e) => error!("Failed to persist {}: {:?}", counter.name(), e),
+            }
+        }
+    }
+}
+
+struct DeviceLocation {
+    nvs: Option<EspDefaultNvs>,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl DeviceLocation {
+    fn open(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = match EspNvs::new(partition, LOCATION_NVS_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                error!("Failed to open location NVS namespace, using the default location: {:?}", e);
+                None
+            }
+        };
+        // Stored as the bit patterns of the f64 coordinates
+        let stored = |key: &str| nvs.as_ref().and_then(|nvs| nvs.get_u64(key).ok().flatten()).map(f64::from_bits);
+        let (latitude, longitude) = match (stored("lat"), stored("lon")) {
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ => (DEFAULT_CONFIG.latitude, DEFAULT_CONFIG.longitude),
+        };
+        info!("Device location: {}, {}", latitude, longitude);
+        Self { nvs, latitude, longitude }
+    }
+
+    // setLocation RPC: validates, persists and applies the coordinates from "lat" and "lon"
+    fn set(&mut self, params: &Value) -> Result<Value> {
+        let coordinate = |name: &str, limit: f64| -> Result<f64> {
+            let value = params.get(name).and_then(Value::as_f64)
+                .ok_or_else(|| anyhow!("Missing or non-numeric {}", name))?;
+            if !(-limit..=limit).contains(&value) {
+                return Err(anyhow!("{} {} out of range -{}..{}", name, value, limit, limit));
+            }
+            Ok(value)
+        };
+        let latitude = coordinate("lat", 90.0)?;
+        let longitude = coordinate("lon", 180.0)?;
+        let nvs = self.nvs.as_ref().ok_or_else(|| anyhow!("Location storage unavailable"))?;
+        nvs.set_u64("lat", latitude.to_bits()).map_err(|e| anyhow!("Failed to persist latitude: {:?}", e))?;
+        nvs.set_u64("lon", longitude.to_bits()).map_err(|e| anyhow!("Failed to persist longitude: {:?}", e))?;
+        self.latitude = latitude;
+        self.longitude = longitude;
+        info!("Device location set to {}, {}", latitude, longitude);
+        Ok(json!({ "lat": latitude, "lon": longitude }))
+    }
+}
+
+#[derive(Clone)]
+struct BrokerSettings {
+    url: String,
+    username: String,
+    password: String,
+}
+
+impl BrokerSettings {
+    // setBroker RPC parameters "url", "user" and "pass"; the password may be empty
+    fn from_params(params: &Value) -> Result<Self> {
+        let field = |name: &str| -> Result<String> {
+            let value = params.get(name).and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("Missing or non-string {}", name))?;
+            if value.len() > BROKER_FIELD_MAX_BYTES || value.contains('\0') {
+                return Err(anyhow!("Invalid {}: at most {} bytes and no NUL characters", name, BROKER_FIELD_MAX_BYTES));
+            }
+            Ok(value.to_string())
+        };
+        let url = field("url")?;
+        let host = ["mqtt://", "mqtts://", "ws://", "wss://"].iter()
+            .find_map(|scheme| url.strip_prefix(scheme))
+            .ok_or_else(|| anyhow!("Unsupported broker URL {}, expected mqtt://, mqtts://, ws:// or wss://", url))?;
+        if host.is_empty() || host.starts_with(':') || host.contains(char::is_whitespace) {
+            return Err(anyhow!("Broker URL {} has no valid host", url));
+        }
+        let username = field("user")?;
+        if username.is_empty() {
+            return Err(anyhow!("Broker user must not be empty"));
+        }
+        Ok(Self { url, username, password: field("pass")? })
+    }
+}
+
+// Broker settings in use, with the previous ones kept in NVS while a new broker is on trial
+struct BrokerConfig {
+    nvs: Option<EspDefaultNvs>,
+    settings: BrokerSettings,
+    on_trial: bool,
+    // When this boot started trying the settings, for BROKER_TRIAL_TIMEOUT_MS
+    trial_started_tick: u32,
+    restart_pending: bool,
+}
+
+impl BrokerConfig {
+    fn open(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = match EspNvs::new(partition, BROKER_NVS_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                error!("Failed to open broker NVS namespace, using the built-in broker: {:?}", e);
+                None
+            }
+        };
+        let (settings, on_trial) = match nvs.as_ref().and_then(|nvs| Self::load(nvs, "")) {
+            Some(settings) => (settings, nvs.as_ref().and_then(|nvs| nvs.get_u8("trial").ok().flatten()) == Some(1)),
+            None => (Self::built_in(), false),
+        };
+        info!("MQTT broker: {} as {}{}", settings.url, settings.username, if on_trial { " (on trial)" } else { "" });
+        Self { nvs, settings, on_trial, trial_started_tick: unsafe { xTaskGetTickCount() }, restart_pending: false }
+    }
+
+    fn built_in() -> BrokerSettings {
+        BrokerSettings {
+            url: DEFAULT_CONFIG.mqtt_broker_url.to_string(),
+            username: DEFAULT_CONFIG.mqtt_username.to_string(),
+            password: DEFAULT_CONFIG.mqtt_password.to_string(),
+        }
+    }
+
+    // Settings stored under "<prefix>url", "<prefix>user" and "<prefix>pass"
+    fn load(nvs: &EspDefaultNvs, prefix: &str) -> Option<BrokerSettings> {
+        let mut buf = [0u8; BROKER_FIELD_MAX_BYTES + 1];
+        let mut get = |name: &str| -> Option<String> {
+            nvs.get_str(&format!("{}{}", prefix, name), &mut buf).ok().flatten().map(|value| value.to_string())
+        };
+        Some(BrokerSettings { url: get("url")?, username: get("user")?, password: get("pass")? })
+    }
+
+    fn store(nvs: &mut EspDefaultNvs, prefix: &str, settings: &BrokerSettings) -> Result<()> {
+        for (name, value) in [("url", &settings.url), ("user", &settings.username), ("pass", &settings.password)] {
+            nvs.set_str(&format!("{}{}", prefix, name), value)
+                .map_err(|e| anyhow!("Failed to persist broker {}: {:?}", name, e))?;
+        }
+        Ok(())
+    }
+
+    // setBroker RPC: validates and persists the new broker, which is applied by a reboot once the reply is out.
+    // A broker still on trial has never been confirmed, so the stored fallback is kept rather than replaced by it.
+    fn set(&mut self, params: &Value) -> Result<Value> {
+        let settings = BrokerSettings::from_params(params)?;
+        let nvs = self.nvs.as_mut().ok_or_else(|| anyhow!("Broker storage unavailable"))?;
+        if !self.on_trial {
+            Self::store(nvs, "prev_", &self.settings)?;
+        }
+        Self::store(nvs, "", &settings)?;
+        nvs.set_u8("trial", 1).map_err(|e| anyhow!("Failed to persist broker trial flag: {:?}", e))?;
+        info!("MQTT broker set to {} as {}, rebooting to apply", settings.url, settings.username);
+        self.restart_pending = true;
+        Ok(json!({ "url": settings.url, "user": settings.username, "restarting": true }))
+    }
+
+    // The first session on a broker under trial makes it permanent
+    fn confirm(&mut self) {
+        self.on_trial = false;
+        if let Some(nvs) = self.nvs.as_ref() {
+            if let Err(e) = nvs.set_u8("trial", 0) {
+                error!("Failed to clear broker trial flag: {:?}", e);
+            }
+        }
+        info!("MQTT broker {} confirmed", self.settings.url);
+    }
+
+    fn trial_expired(&self) -> bool {
+        self.on_trial
+            && ticks_elapsed(unsafe { xTaskGetTickCount() }, self.trial_started_tick).unwrap_or(u32::MAX) >= ms_to_ticks(BROKER_TRIAL_TIMEOUT_MS)
+    }
+
+    // Puts the previous broker back in NVS; the caller reboots to apply it
+    fn revert(&mut self) {
+        let Some(nvs) = self.nvs.as_mut() else {
+            return;
+        };
+        let previous = Self::load(nvs, "prev_").unwrap_or_else(Self::built_in);
+        error!("MQTT broker {} unreachable, reverting to {}", self.settings.url, previous.url);
+        if let Err(e) = Self::store(nvs, "", &previous) {
+            error!("{:?}", e);
+        }
+        if let Err(e) = nvs.set_u8("trial", 0) {
+            error!("Failed to clear broker trial flag: {:?}", e);
+        }
+        self.settings = previous;
+        self.on_trial = false;
+    }
+}
+
+struct FirmwareValidation {
+    pending: bool,
+    started_tick: u32,
+    last_report_tick: u32,
+}
+
+impl FirmwareValidation {
+    fn start() -> Self {
+        let mut state: esp_ota_img_states_t = esp_ota_img_states_t_ESP_OTA_IMG_UNDEFINED;
+        let res = unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+        let pending = res == ESP_OK && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY;
+        if pending {
+            info!("Running image is pending verification: needs {} consecutive publishes and {} s uptime within {} s",
+                OTA_VALIDATION_MIN_PUBLISHES, OTA_VALIDATION_MIN_UPTIME_MS / 1000, OTA_VALIDATION_DEADLINE_MS / 1000);
+        }
+        let now = unsafe { xTaskGetTickCount() };
+        Self { pending, started_tick: now, last_report_tick: now }
+    }
+
+    // Marks the image valid once it has proven itself, or rolls back once the deadline passes
+    fn poll(&mut self) {
+        if !self.pending {
+            return;
+        }
+        // A start tick ahead of the counter counts as no time elapsed, so it can never trigger a rollback
+        let elapsed = ticks_elapsed(unsafe { xTaskGetTickCount() }, self.started_tick).unwrap_or(0);
+        let publishes = TELEMETRY_PUBLISH_STREAK.load(Ordering::Relaxed);
+        if publishes >= OTA_VALIDATION_MIN_PUBLISHES && elapsed >= ms_to_ticks(OTA_VALIDATION_MIN_UPTIME_MS) {
+            let res = unsafe { esp_ota_mark_app_valid_cancel_rollback() };
+            if res == ESP_OK {
+                info!("Firmware validated after {} consecutive publishes, rollback cancelled", publishes);
+                self.pending = false;
+            } else {
+                error!("Failed to mark firmware valid: {}", res);
+            }
+        } else if elapsed >= ms_to_ticks(OTA_VALIDATION_DEADLINE_MS) {
+            error!("Firmware not validated within {} s ({} consecutive publishes), rolling back",
+                OTA_VALIDATION_DEADLINE_MS / 1000, publishes);
+            unsafe { esp_ota_mark_app_invalid_rollback_and_reboot(); }
+        }
+    }
+
+    fn report_due(&mut self) -> bool {
+        let now = unsafe { xTaskGetTickCount() };
+        if ticks_elapsed(now, self.last_report_tick).unwrap_or(u32::MAX) < ms_to_ticks(OTA_VALIDATION_REPORT_INTERVAL_MS) {
+            return false;
+        }
+        self.last_report_tick = now;
+        true
+    }
+
+    fn to_json(&self) -> Value {
+        let elapsed_s = ticks_elapsed(unsafe { xTaskGetTickCount() }, self.started_tick).unwrap_or(0) as u64 / configTICK_RATE_HZ as u64;
+        json!({
+            "fw_validation": if self.pending { "PENDING" } else { "VALID" },
+            "fw_validation_publishes": TELEMETRY_PUBLISH_STREAK.load(Ordering::Relaxed),
+            "fw_validation_publishes_required": OTA_VALIDATION_MIN_PUBLISHES,
+            "fw_validation_uptime_s": elapsed_s,
+            "fw_validation_uptime_required_s": OTA_VALIDATION_MIN_UPTIME_MS / 1000
+        })
+    }
+}
+
+struct TelemetryBoost {
+    interval_ms: u32,
+    started_tick: u32,
+    duration_ticks: u32,
+    active: bool,
+}
+
+impl TelemetryBoost {
+    fn new() -> Self {
+        Self { interval_ms: DEFAULT_CONFIG.sensor_sample_interval_ms, started_tick: 0, duration_ticks: 0, active: false }
+    }
+
+    fn start(&mut self, params: &Value) -> Result<Value> {
+        let seconds = params.get("seconds").and_then(|v| v.as_u64())
+            .filter(|&s| s > 0 && s <= TELEMETRY_BOOST_MAX_SECONDS as u64)
+            .ok_or_else(|| anyhow!("seconds must be between 1 and {}", TELEMETRY_BOOST_MAX_SECONDS))? as u32;
+        let interval_ms = params.get("interval_ms").and_then(|v| v.as_u64())
+            .filter(|&i| i >= TELEMETRY_BOOST_MIN_INTERVAL_MS as u64 && i <= DEFAULT_CONFIG.sensor_sample_interval_ms as u64)
+            .ok_or_else(|| anyhow!("interval_ms must be between {} and {}", TELEMETRY_BOOST_MIN_INTERVAL_MS, DEFAULT_CONFIG.sensor_sample_interval_ms))? as u32;
+        self.interval_ms = interval_ms;
+        self.started_tick = unsafe { xTaskGetTickCount() };
+        self.duration_ticks = ms_to_ticks(seconds * 1000);
+        self.active = true;
+        info!("Telemetry boosted to every {} ms for {} s", interval_ms, seconds);
+        Ok(self.to_json())
+    }
+
+    // Sampling interval for the normal loop; reverts once the boost window has passed
+    fn interval_ms(&mut self) -> u32 {
+        if self.active && ticks_elapsed(unsafe { xTaskGetTickCount() }, self.started_tick).unwrap_or(u32::MAX) >= self.duration_ticks {
+            self.active = false;
+            info!("Telemetry boost ended, back to every {} ms", DEFAULT_CONFIG.sensor_sample_interval_ms);
+        }
+        if self.active { self.interval_ms } else { DEFAULT_CONFIG.sensor_sample_interval_ms }
+    }
+
+    fn to_json(&self) -> Value {
+        if !self.active {
+            return json!({ "active": false, "interval_ms": DEFAULT_CONFIG.sensor_sample_interval_ms });
+        }
+        let elapsed = ticks_elapsed(unsafe { xTaskGetTickCount() }, self.started_tick).unwrap_or(u32::MAX);
+        let remaining_ms = self.duration_ticks.saturating_sub(elapsed) as u64 * 1000 / configTICK_RATE_HZ as u64;
+        json!({
+            "active": true,
+            "interval_ms": self.interval_ms,
+            "remaining_s": remaining_ms / 1000
+        })
+    }
+}
+
+fn publish_telemetry(mqtt_client: &SimpleMqttClient, payload: &Value) -> Result<()> {
+    let result = publish_telemetry_mqtt(mqtt_client, payload);
+    // The mirror runs after the MQTT publish and its failures are only logged
+    #[cfg(feature = "http-mirror")]
+    if let Err(e) = mirror_telemetry(payload) {
+        error!("Failed to mirror telemetry over HTTP: {:?}", e);
+    }
+    result
+}
+
+#[cfg(feature = "http-mirror")]
+fn mirror_telemetry(payload: &Value) -> Result<()> {
+    let body = payload.to_string();
+    unsafe {
+        let config = esp_http_client_config_t {
+            url: HTTP_MIRROR_URL.as_ptr(),
+            method: esp_http_client_method_t_HTTP_METHOD_POST,
+            timeout_ms: HTTP_MIRROR_TIMEOUT_MS,
+            ..Default::default()
+        };
+        let client = esp_http_client_init(&config);
+        if client.is_null() {
+            return Err(anyhow!("Failed to initialize HTTP client"));
+        }
+        esp_http_client_set_header(client, c"Content-Type".as_ptr(), c"application/json".as_ptr());
+        esp_http_client_set_post_field(client, body.as_ptr() as *const c_char, body.len() as i32);
+        let res = esp_http_client_perform(client);
+        let status = esp_http_client_get_status_code(client);
+        esp_http_client_cleanup(client);
+        if res != ESP_OK {
+            return Err(anyhow!("HTTP POST failed, error code: {}", res));
+        }
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("HTTP POST returned status {}", status));
+        }
+    }
+    Ok(())
+}
+
+fn publish_telemetry_mqtt(mqtt_client: &SimpleMqttClient, payload: &Value) -> Result<()> {
+    let result = publish_telemetry_encoded(mqtt_client, payload);
+    match result {
+        Ok(()) => {
+            LifetimeCounter::TelemetryPublished.increment();
+            TELEMETRY_PUBLISH_STREAK.fetch_add(1, Ordering::Relaxed);
+            TELEMETRY_PUBLISH_FAILURES.store(0, Ordering::Relaxed);
+        }
+        Err(_) => {
+            TELEMETRY_PUBLISH_STREAK.store(0, Ordering::Relaxed);
+            TELEMETRY_PUBLISH_FAILURES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+// Wraps a raw deflate stream in the gzip header and CRC-32/length trailer
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let deflated = miniz_oxide::deflate::compress_to_vec(data, TELEMETRY_GZIP_LEVEL);
+    let mut out = Vec::with_capacity(deflated.len() + 18);
+    // Magic, deflate method, no flags, no mtime, no extra flags, OS unknown
+    out.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xff]);
+    out.extend_from_slice(&deflated);
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+// CRC-32 (reflected, polynomial 0xedb88320) as used by the gzip trailer
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn publish_telemetry_encoded(mqtt_client: &SimpleMqttClient, payload: &Value) -> Result<()> {
+    match TELEMETRY_ENCODING {
+        TelemetryEncoding::Json => {
+            let json = payload.to_string();
+            if TELEMETRY_GZIP_ENABLED && json.len() >= TELEMETRY_GZIP_MIN_BYTES {
+                let compressed = gzip(json.as_bytes());
+                info!("Telemetry gzipped from {} to {} bytes", json.len(), compressed.len());
+                mqtt_client.publish_with_qos(TELEMETRY_GZIP_TOPIC, &compressed, TELEMETRY_QOS)
+            } else {
+                mqtt_client.publish_with_qos(OTA_TELEMETRY_TOPIC, json.as_bytes(), TELEMETRY_QOS)
+            }
+        }
+        TelemetryEncoding::Cbor => {
+            let mut encoded = Vec::new();
+            ciborium::into_writer(payload, &mut encoded)
+                .map_err(|e| anyhow!("Failed to encode telemetry as CBOR: {:?}", e))?;
+            mqtt_client.publish_with_qos(TELEMETRY_CBOR_TOPIC, &encoded, TELEMETRY_QOS)
+        }
+    }
+}
+
+impl TelemetrySink {
+    fn publish(&self, mqtt_client: &SimpleMqttClient, values: &serde_json::Map<String, Value>) -> Result<()> {
+        let mapped: serde_json::Map<String, Value> = if self.fields.is_empty() {
+            values.clone()
+        } else {
+            self.fields.iter()
+                .filter_map(|&(key, name)| values.get(key).map(|value| (name.to_string(), value.clone())))
+                .collect()
+        };
+        let payload = Value::Object(mapped);
+        match self.format {
+            SinkFormat::Json => mqtt_client.publish_with_qos(self.topic, payload.to_string().as_bytes(), self.qos),
+            SinkFormat::Cbor => {
+                let mut encoded = Vec::new();
+                ciborium::into_writer(&payload, &mut encoded)
+                    .map_err(|e| anyhow!("Failed to encode telemetry as CBOR: {:?}", e))?;
+                mqtt_client.publish_with_qos(self.topic, &encoded, self.qos)
+            }
+        }
+    }
+}
+
+fn publish_to_sinks(mqtt_client: &SimpleMqttClient, values: &Value, reading: &ReadingSnapshot, time_sync: &TimeSync) {
+    let Value::Object(values) = values else {
+        return;
+    };
+    let mut values = values.clone();
+    if time_sync.has_synced() {
+        values.insert("ts".to_string(), json!(reading.timestamp));
+    }
+    for sink in TELEMETRY_SINKS {
+        if let Err(e) = sink.publish(mqtt_client, &values) {
+            error!("Failed to publish telemetry to sink {}: {:?}", sink.topic, e);
+        }
+    }
+}
+
+// Devices with none of their fields in this reading are left out; nothing is sent if that leaves the payload empty
+fn publish_gateway_telemetry(mqtt_client: &SimpleMqttClient, values: &Value, reading: &ReadingSnapshot, time_sync: &TimeSync) -> Result<()> {
+    let Value::Object(values) = values else {
+        return Ok(());
+    };
+    let mut payload = serde_json::Map::new();
+    for device in GATEWAY_DEVICES {
+        let device_values: serde_json::Map<String, Value> = device.fields.iter()
+            .filter_map(|&(key, name)| values.get(key).map(|value| (name.to_string(), value.clone())))
+            .collect();
+        if device_values.is_empty() {
+            continue;
+        }
+        // Without a wall clock ThingsBoard stamps the entry with its own receive time
+        let entry = if time_sync.has_synced() {
+            json!({ "ts": reading.timestamp, "values": device_values })
+        } else {
+            Value::Object(device_values)
+        };
+        payload.insert(device.name.to_string(), json!([entry]));
+    }
+    if payload.is_empty() {
+        return Ok(());
+    }
+    mqtt_client.publish(GATEWAY_TELEMETRY_TOPIC, &Value::Object(payload).to_string())
+}
+
+fn current_timestamp_ms() -> u64 {
+    unsafe {
+        let mut tv: timeval = core::mem::zeroed();
+        gettimeofday(&mut tv, core::ptr::null_mut());
+        tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000
+    }
+}
+
+// One pass over the sensors: BME280, CO2 (fault-checked and smoothed) and the chip temperature
+struct ReadingSnapshot {
+    temperature: f32,
+    humidity: f32,
+    pressure: f32,
+    co2_ppm: Option<f32>,
+    co2_adc_raw: Option<i32>,
+    // Why co2_ppm is missing when the ADC read itself failed
+    co2_read_error: Option<String>,
+    co2_sensor_fault: bool,
+    chip_temperature: Option<f32>,
+    latitude: f64,
+    longitude: f64,
+    timestamp: u64,
+    uptime_ms: i64,
+}
+
+impl ReadingSnapshot {
+    fn log(&self, counter: u32) {
+        info!("=== Reading {} ===", counter);
+        info!("Temperature: {:.2} °C", self.temperature);
+        info!("Humidity: {:.2} %", self.humidity);
+        info!("Pressure: {:.2} hPa", self.pressure / 100.0);
+        match self.co2_ppm {
+            Some(ppm) => info!("CO2 Concentration: {:.2} ppm", ppm),
+            None if self.co2_read_error.is_some() => info!("CO2 Concentration: unavailable (read error)"),
+            None => info!("CO2 Concentration: unavailable (sensor fault)"),
+        }
+    }
+}
+
+// The driver waits a fixed 40 ms between triggering a forced conversion and reading it back, which is too
+// short for high oversampling; waits are stretched to the conversion time of the current settings
+struct ConversionDelay {
+    min_ms: u32,
+}
+
+impl DelayNs for ConversionDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        Ets.delay_ns(ns);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        Ets.delay_ms(ms.max(self.min_ms));
+    }
+}
+
+// t_sb field of the config register (datasheet table 27)
+fn bme280_standby_code(standby_ms: f32) -> Option<u8> {
+    [0.5, 62.5, 125.0, 250.0, 500.0, 1000.0, 10.0, 20.0].iter()
+        .position(|&ms| ms == standby_ms)
+        .map(|code| code as u8)
+}
+
+// Applies the settings and, in normal mode, restarts the continuous conversions. init_with_config soft-resets
+// the sensor, so this is needed after every (re)configuration.
+fn init_bme280(bme280: &mut BME280<I2cDriver<'static>>, settings: &Bme280Settings) -> Result<()> {
+    let config = settings.to_configuration()?;
+    bme280.init_with_config(&mut Ets, config).map_err(|e| anyhow!("{:?}", e))?;
+    if BME280_MODE == Bme280Mode::Normal {
+        settings.start_normal_mode()?;
+    }
+    Ok(())
+}
 
-                        for subtype in &[esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_0, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_1] {
-                            let mut iterator = esp_partition_find(
-                                esp_partition_type_t_ESP_PARTITION_TYPE_APP,
-                                *subtype,
-                                core::ptr::null()
-                            );
-                            while !iterator.is_null() {
-                                let partition = esp_partition_get(iterator);
-                                let label = core::ffi::CStr::from_ptr((*partition).label.as_ptr()).to_str().unwrap_or("unknown");
-                                info!("Checking partition: {}, subtype: {:?}, address: 0x{:x}", label, *subtype, (*partition).address);
-                                if !running_partition.is_null() && partition != running_partition {
-                                    self.ota_partition = partition;
-                                    break;
-                                }
-                                iterator = esp_partition_next(iterator);
-                            }
-                            esp_partition_iterator_release(iterator);
-                            if !self.ota_partition.is_null() {
-                                break;
-                            }
-                        }
-                    }
+// Trimming parameters for compensating normal-mode readings, which bypass the bme280 driver (datasheet
+// section 4.2.2). Stored as f64 for the floating point compensation formulas of section 8.1.
+struct Bme280Calibration {
+    t: [f64; 3],
+    p: [f64; 9],
+    h: [f64; 6],
+}
 
-                    if self.ota_partition.is_null() {
-                        error!("No valid OTA partition found for update");
-                        self.ota_state = OtaState::Failed("No valid OTA partition found".to_string());
-                        result = Err(anyhow!("No valid OTA partition found"));
-                    } else {
-                        let label = core::ffi::CStr::from_ptr((*self.ota_partition).label.as_ptr()).to_str().unwrap_or("unknown");
-                        info!("Selected OTA partition: {}, address: 0x{:x}, size: 0x{:x}",
-                            label, (*self.ota_partition).address, (*self.ota_partition).size);
-                        
-                        let res = esp_partition_erase_range(self.ota_partition, 0, (*self.ota_partition).size as usize);
-                        if res != ESP_OK {
-                            self.ota_state = OtaState::Failed(format!("Failed to erase OTA partition: {}", res));
-                            result = Err(anyhow!("Failed to erase OTA partition: {}", res));
-                        } else {
-                            let res = esp_ota_begin(self.ota_partition, self.fw_size.unwrap_or(0) as usize, &mut self.ota_handle);
-                            if res != ESP_OK {
-                                self.ota_state = OtaState::Failed(format!("Failed to begin OTA: {}", res));
-                                result = Err(anyhow!("Failed to begin OTA: {}", res));
-                            } else {
-                                for i in 0..3 {
-                                    if let Err(e) = self.request_firmware_chunk(mqtt_client, self.current_chunk + i) {
-                                        self.ota_state = OtaState::Failed(format!("Failed to request firmware chunk: {}", e));
-                                        result = Err(e);
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    if let Err(e) = self.send_ota_telemetry(mqtt_client) {
-                        error!("Failed to send OTA telemetry: {:?}", e);
-                    }
-                }
+impl Bme280Calibration {
+    fn read() -> Result<Self> {
+        let address = BME280_ADDRESSES[0];
+        let mut tp = [0u8; 24];
+        i2c_read_registers(address, 0x88, &mut tp)?;
+        let mut h1 = [0u8; 1];
+        i2c_read_registers(address, 0xa1, &mut h1)?;
+        let mut h = [0u8; 7];
+        i2c_read_registers(address, 0xe1, &mut h)?;
+
+        // dig_T1 and dig_P1 are unsigned, the other words signed
+        let word = |i: usize| u16::from_le_bytes([tp[2 * i], tp[2 * i + 1]]);
+        let mut p = [0.0; 9];
+        for (i, value) in p.iter_mut().enumerate() {
+            *value = if i == 0 { word(3) as f64 } else { word(3 + i) as i16 as f64 };
+        }
+        Ok(Self {
+            t: [word(0) as f64, word(1) as i16 as f64, word(2) as i16 as f64],
+            p,
+            h: [
+                h1[0] as f64,
+                i16::from_le_bytes([h[0], h[1]]) as f64,
+                h[2] as f64,
+                ((h[3] as i8 as i16) << 4 | (h[4] & 0x0f) as i16) as f64,
+                ((h[5] as i8 as i16) << 4 | (h[4] >> 4) as i16) as f64,
+                h[6] as i8 as f64,
+            ],
+        })
+    }
+
+    // Latest completed conversion as (temperature °C, humidity %, pressure Pa). The data registers are shadowed,
+    // so a conversion finishing mid-read cannot mix two results.
+    fn read_latest(&self) -> Result<(f32, f32, f32)> {
+        let mut data = [0u8; 8];
+        i2c_read_registers(BME280_ADDRESSES[0], 0xf7, &mut data)?;
+        let adc_p = ((data[0] as u32) << 12 | (data[1] as u32) << 4 | (data[2] as u32) >> 4) as f64;
+        let adc_t = (data[3] as u32) << 12 | (data[4] as u32) << 4 | (data[5] as u32) >> 4;
+        let adc_h = ((data[6] as u32) << 8 | data[7] as u32) as f64;
+        // 0x80000 is the reset value, left in place until the first conversion completes
+        if adc_t == 0x80000 {
+            return Err(anyhow!("BME280 has not completed a normal-mode conversion yet"));
+        }
+        let adc_t = adc_t as f64;
+        let [t1, t2, t3] = self.t;
+        let [p1, p2, p3, p4, p5, p6, p7, p8, p9] = self.p;
+        let [h1, h2, h3, h4, h5, h6] = self.h;
+
+        let var1 = (adc_t / 16384.0 - t1 / 1024.0) * t2;
+        let var2 = (adc_t / 131072.0 - t1 / 8192.0) * (adc_t / 131072.0 - t1 / 8192.0) * t3;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p6 / 32768.0;
+        var2 += var1 * p5 * 2.0;
+        var2 = var2 / 4.0 + p4 * 65536.0;
+        var1 = (p3 * var1 * var1 / 524288.0 + p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p1;
+        if var1 == 0.0 {
+            return Err(anyhow!("BME280 pressure compensation failed (dig_P1 is zero)"));
+        }
+        let mut pressure = 1048576.0 - adc_p;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = p9 * pressure * pressure / 2147483648.0;
+        let var2 = pressure * p8 / 32768.0;
+        pressure += (var1 + var2 + p7) / 16.0;
+
+        let var_h = t_fine - 76800.0;
+        let var_h = (adc_h - (h4 * 64.0 + h5 / 16384.0 * var_h))
+            * (h2 / 65536.0 * (1.0 + h6 / 67108864.0 * var_h * (1.0 + h3 / 67108864.0 * var_h)));
+        let humidity = (var_h * (1.0 - h1 * var_h / 524288.0)).clamp(0.0, 100.0);
+
+        Ok((temperature as f32, humidity as f32, pressure as f32))
+    }
+}
+
+// Fails only when the BME280 cannot be read; the caller owns I2C bus recovery. Normal mode passes the
+// calibration and reads the latest conversion; forced mode triggers one through the driver.
+#[allow(clippy::too_many_arguments)]
+fn acquire_reading(
+    bme280: &mut BME280<I2cDriver<'static>>,
+    bme280_settings: &Bme280Settings,
+    bme280_calibration: Option<&Bme280Calibration>,
+    co2_adc: &Co2Adc,
+    co2_fault_detector: &mut Co2FaultDetector,
+    co2_filter: &mut EmaFilter,
+    chip_temperature: &ChipTemperature,
+    location: (f64, f64)
+) -> Result<ReadingSnapshot> {
+    let (temperature, humidity, pressure) = match bme280_calibration {
+        Some(calibration) => calibration.read_latest().map_err(|e| anyhow!("BME280 read error: {:?}", e))?,
+        None => {
+            let mut delay = ConversionDelay { min_ms: bme280_settings.measurement_time_ms() };
+            let measurements = bme280.measure(&mut delay).map_err(|e| anyhow!("BME280 read error: {:?}", e))?;
+            (measurements.temperature, measurements.humidity, measurements.pressure)
+        }
+    };
+
+    let co2_adc_raw = co2_adc.read();
+    let co2_ppm = match co2_adc_raw {
+        Ok(value) => {
+            let was_faulted = co2_fault_detector.is_faulted();
+            if co2_fault_detector.update(value) {
+                None
             } else {
-                info!("No new firmware detected: title and version match current");
+                // Pre-fault history must not bleed into the recovered readings
+                if was_faulted {
+                    co2_filter.reset();
+                }
+                let ppm = compensate_co2_ppm(adc_to_ppm(value), temperature, humidity);
+                Some(co2_filter.update(ppm))
             }
-        } else {
-            info!("Incomplete firmware attributes: fw_title={:?}, fw_version={:?}", self.fw_title, self.fw_version);
-            result = Err(anyhow!("Incomplete firmware attributes received"));
         }
-        result
-    }   
+        Err(ref e) => {
+            error!("{:?}", e);
+            None
+        }
+    };
 
-    fn request_firmware_info(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        self.request_id += 1;
-        let request_topic = format!("{}{}", OTA_REQUEST_TOPIC, self.request_id);
-        let payload = json!({
-            "sharedKeys": format!("{},{},{},{},{}",
-                FW_TITLE_ATTR, FW_VERSION_ATTR, FW_SIZE_ATTR, FW_CHECKSUM_ATTR, FW_CHECKSUM_ALG_ATTR)
+    Ok(ReadingSnapshot {
+        temperature,
+        humidity,
+        pressure,
+        co2_ppm,
+        co2_read_error: co2_adc_raw.as_ref().err().map(|e| e.to_string()),
+        co2_adc_raw: co2_adc_raw.ok(),
+        co2_sensor_fault: co2_fault_detector.is_faulted(),
+        chip_temperature: chip_temperature.read(),
+        latitude: location.0,
+        longitude: location.1,
+        timestamp: current_timestamp_ms(),
+        uptime_ms: unsafe { esp_timer_get_time() } / 1000,
+    })
+}
+
+// Everything a sample touches; the main loop still needs it for RPCs that reconfigure the BME280
+struct SensorState {
+    bme280: BME280<I2cDriver<'static>>,
+    bme280_settings: Bme280Settings,
+    // Some only in normal mode
+    bme280_calibration: Option<Bme280Calibration>,
+    co2_adc: Co2Adc,
+    co2_fault_detector: Co2FaultDetector,
+    co2_adc_recovery: Co2AdcRecovery,
+    co2_filter: EmaFilter,
+    chip_temperature: ChipTemperature,
+    i2c_bus_monitor: I2cBusMonitor,
+}
+
+// Readings and settings the main loop and the sensor task hand each other
+struct SensorExchange {
+    latest_readings: LatestReadings,
+    location: (f64, f64),
+    samples: Vec<Result<ReadingSnapshot>>,
+    dropped_samples: u32,
+}
+
+// Sensors shared between the main loop and the sensor task. The state is locked for a whole sample, so the
+// BME280 and the I2C bus are never used from both tasks at once; the exchange sits behind a shorter lock. All
+// methods take &self, so both tasks can hold a reference at the same time.
+struct SensorHub {
+    // None once released before a restart
+    state: Guarded<Option<SensorState>>,
+    exchange: Guarded<SensorExchange>,
+    interval_ms: AtomicU32,
+    paused: AtomicBool,
+}
+
+impl SensorHub {
+    fn new(state: SensorState, location: (f64, f64)) -> Self {
+        Self {
+            state: Guarded::new(Some(state)),
+            exchange: Guarded::new(SensorExchange {
+                latest_readings: LatestReadings::default(),
+                location,
+                samples: Vec::new(),
+                dropped_samples: 0,
+            }),
+            interval_ms: AtomicU32::new(DEFAULT_CONFIG.sensor_sample_interval_ms),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    // Reads every sensor once, running I2C bus recovery when the BME280 keeps failing
+    fn sample(&self) -> Result<ReadingSnapshot> {
+        let location = self.exchange.with(|exchange| exchange.location);
+        self.state.with(|slot| {
+            let mut state = slot.take().ok_or_else(|| anyhow!("Sensors released"))?;
+            let mut result = acquire_reading(
+                &mut state.bme280, &state.bme280_settings, state.bme280_calibration.as_ref(), &state.co2_adc, &mut state.co2_fault_detector,
+                &mut state.co2_filter, &state.chip_temperature, location
+            );
+            match &mut result {
+                Ok(reading) => {
+                    state.i2c_bus_monitor.record_success();
+                    state.co2_adc_recovery.record(&mut state.co2_adc, reading.co2_read_error.is_none());
+                    reading.co2_sensor_fault |= state.co2_adc_recovery.failed;
+                    self.exchange.with(|exchange| {
+                        exchange.latest_readings.update(reading.temperature, reading.humidity, reading.pressure, reading.co2_ppm);
+                    });
+                }
+                Err(_) => {
+                    if state.i2c_bus_monitor.record_failure() {
+                        state.bme280 = state.i2c_bus_monitor.recover(state.bme280, &state.bme280_settings);
+                    }
+                }
+            }
+            *slot = Some(state);
+            result
+        })
+    }
+
+    // Runs `f` with the sensors locked against the sensor task; None once they have been released
+    fn with_state<R>(&self, f: impl FnOnce(&mut SensorState) -> R) -> Option<R> {
+        self.state.with(|state| state.as_mut().map(f))
+    }
+
+    // Drops the sensor drivers ahead of a restart, waiting out a sample in progress
+    fn release(&self) {
+        self.state.with(|state| *state = None);
+    }
+
+    fn latest_readings(&self) -> LatestReadings {
+        self.exchange.with(|exchange| exchange.latest_readings)
+    }
+
+    // Cadence and location for the sensor task, refreshed by the main loop every pass
+    fn configure(&self, interval_ms: u32, paused: bool, location: (f64, f64)) {
+        self.interval_ms.store(interval_ms, Ordering::Release);
+        self.paused.store(paused, Ordering::Release);
+        self.exchange.with(|exchange| exchange.location = location);
+    }
+
+    fn push_sample(&self, sample: Result<ReadingSnapshot>) {
+        self.exchange.with(|exchange| {
+            if exchange.samples.len() >= SENSOR_TASK_QUEUE_CAPACITY {
+                let _ = exchange.samples.remove(0);
+                exchange.dropped_samples = exchange.dropped_samples.saturating_add(1);
+                warn!("Sensor queue full, dropped the oldest reading ({} so far)", exchange.dropped_samples);
+            }
+            exchange.samples.push(sample);
         });
-        Self::mqtt_publish(mqtt_client, &request_topic, &payload.to_string())?;
-        info!("Requested firmware info, topic: {}", request_topic);
+    }
+
+    fn take_samples(&self) -> Vec<Result<ReadingSnapshot>> {
+        self.exchange.with(|exchange| core::mem::take(&mut exchange.samples))
+    }
+
+    // The hub is leaked at startup, so the reference handed to the task stays valid for the whole run
+    fn spawn_task(&'static self) -> Result<()> {
+        let mut handle: TaskHandle_t = core::ptr::null_mut();
+        let res = unsafe {
+            xTaskCreatePinnedToCore(
+                Some(Self::task),
+                c"sensors".as_ptr(),
+                SENSOR_TASK_STACK_BYTES,
+                self as *const SensorHub as *mut c_void,
+                SENSOR_TASK_PRIORITY,
+                &mut handle,
+                tskNO_AFFINITY as BaseType_t
+            )
+        };
+        if res != pdPASS as BaseType_t {
+            return Err(anyhow!("Failed to create sensor task, error code: {}", res));
+        }
+        info!("Sensor task started, sampling every {} ms", self.interval_ms.load(Ordering::Acquire));
         Ok(())
     }
 
-    fn request_firmware_chunk(&mut self, mqtt_client: *mut esp_mqtt_client, chunk_index: u32) -> Result<()> {
-        if let Some(fw_size) = self.fw_size {
-            if self.received_size >= fw_size as usize {
-                info!("All firmware chunks received, no further requests needed");
-                return Ok(());
+    // Samples on a fixed period measured from the previous wake-up, so publish latency in the main loop does not
+    // shift it. A failed BME280 read is retried after a second, as in the main loop.
+    unsafe extern "C" fn task(arg: *mut c_void) {
+        let hub = &*(arg as *const SensorHub);
+        let mut last_wake = xTaskGetTickCount();
+        loop {
+            if !hub.paused.load(Ordering::Acquire) {
+                let sample = hub.sample();
+                let failed = sample.is_err();
+                hub.push_sample(sample);
+                if failed {
+                    vTaskDelay(ms_to_ticks(1000));
+                    last_wake = xTaskGetTickCount();
+                    continue;
+                }
             }
+            xTaskDelayUntil(&mut last_wake, ms_to_ticks(hub.interval_ms.load(Ordering::Acquire)));
         }
-        let topic = format!("{}/{}/chunk/{}", OTA_FIRMWARE_REQUEST_TOPIC, self.firmware_request_id, chunk_index);
-        let payload = self.chunk_size.to_string();
-        Self::mqtt_publish(mqtt_client, &topic, &payload)?;
-        info!("Requested firmware chunk {}, topic: {}", chunk_index, topic);
-        Ok(())
     }
+}
 
-    fn handle_firmware_chunk(&mut self, data: &[u8], chunk_index: u32, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        if chunk_index == self.current_chunk {
-            if data.len() == 0 {
-                if self.received_size == self.fw_size.unwrap_or(0) as usize {
-                    info!("Received empty chunk, download complete");
-                    self.ota_state = OtaState::Downloaded;
-                    unsafe {
-                        let res = esp_ota_end(self.ota_handle);
-                        if res != ESP_OK {
-                            self.ota_state = OtaState::Failed(format!("Failed to end OTA: {}", res));
-                            self.send_ota_telemetry(mqtt_client)?;
-                            return Err(anyhow!("Failed to end OTA: {}", res));
-                        }
-                    }
-                    self.process_firmware(mqtt_client)?;
-                    return Ok(());
-                } else {
-                    self.ota_state = OtaState::Failed("Received empty chunk but size mismatch".to_string());
-                    self.send_ota_telemetry(mqtt_client)?;
-                    return Err(anyhow!("Empty chunk received prematurely"));
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum HealthStatus {
+    Healthy,
+    Degraded,
+    Critical,
+}
+
+impl HealthStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            HealthStatus::Healthy => "healthy",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Critical => "critical",
+        }
+    }
+}
+
+// Snapshot of the subsystems that feed the aggregate health indicator
+struct SubsystemStatus {
+    wifi_connected: bool,
+    mqtt_connected: bool,
+    sensor_ok: bool,
+    co2_ok: bool,
+    ota_failed: bool,
+    free_heap: u32,
+}
+
+impl SubsystemStatus {
+    fn collect(wifi_connected: bool, mqtt_connected: bool, sensor_ok: bool, co2_ok: bool, ota_failed: bool) -> Self {
+        Self {
+            wifi_connected,
+            mqtt_connected,
+            sensor_ok,
+            co2_ok,
+            ota_failed,
+            free_heap: unsafe { esp_get_free_heap_size() },
+        }
+    }
+
+    // Each failing subsystem with the level it forces. The worst one decides the aggregate:
+    //   critical: BME280 unreadable (no weather data at all), free heap below HEALTH_HEAP_CRITICAL_BYTES
+    //   degraded: WiFi or MQTT down, CO2 sensor fault, last OTA failed, free heap below HEALTH_HEAP_DEGRADED_BYTES
+    fn issues(&self) -> Vec<(&'static str, HealthStatus)> {
+        let mut issues = Vec::new();
+        if !self.sensor_ok {
+            issues.push(("sensor", HealthStatus::Critical));
+        }
+        if self.free_heap < HEALTH_HEAP_CRITICAL_BYTES {
+            issues.push(("heap", HealthStatus::Critical));
+        } else if self.free_heap < HEALTH_HEAP_DEGRADED_BYTES {
+            issues.push(("heap", HealthStatus::Degraded));
+        }
+        if !self.wifi_connected {
+            issues.push(("wifi", HealthStatus::Degraded));
+        }
+        if !self.mqtt_connected {
+            issues.push(("mqtt", HealthStatus::Degraded));
+        }
+        if !self.co2_ok {
+            issues.push(("co2", HealthStatus::Degraded));
+        }
+        if self.ota_failed {
+            issues.push(("ota", HealthStatus::Degraded));
+        }
+        issues
+    }
+
+    fn health(&self) -> HealthStatus {
+        self.issues().iter().fold(HealthStatus::Healthy, |worst, &(_, level)| if level > worst { level } else { worst })
+    }
+
+    fn to_json(&self) -> Value {
+        let issues: Vec<&str> = self.issues().iter().map(|&(name, _)| name).collect();
+        json!({ "health": self.health().as_str(), "health_issues": issues })
+    }
+}
+
+fn send_telemetry(
+    mqtt_client: &SimpleMqttClient,
+    telemetry_batch: &mut TelemetryBatch,
+    boot_backlog: &mut BootBacklog,
+    change_filter: &mut TelemetryChangeFilter,
+    reading: &ReadingSnapshot,
+    time_sync: &TimeSync,
+    extra_values: serde_json::Map<String, Value>
+) -> Result<()> {
+    let co2_warming_up = co2_warming_up();
+    let mut values = json!({
+        "temperature": round_to(reading.temperature, TEMPERATURE_DECIMALS),
+        "humidity": round_to(reading.humidity, HUMIDITY_DECIMALS),
+        "pressure": round_to(reading.pressure / 100.0, PRESSURE_DECIMALS),
+        "co2_ppm": if co2_warming_up { None } else { reading.co2_ppm.map(|ppm| round_to(ppm, CO2_DECIMALS)) },
+        "co2_sensor_fault": reading.co2_sensor_fault,
+        "co2_warming_up": co2_warming_up,
+        "timestamp_reliable": time_sync.is_reliable(),
+        "latitude": reading.latitude,
+        "longitude": reading.longitude
+    });
+    if let Value::Object(map) = &mut values {
+        if SNTP_UPTIME_FALLBACK {
+            map.insert("time_source".to_string(), json!(time_sync.time_source()));
+            if !time_sync.has_synced() {
+                map.insert("uptime_ms".to_string(), json!(reading.uptime_ms));
+            }
+        }
+        let co2_read_failed = reading.co2_read_error.is_some() && !co2_warming_up;
+        match CO2_READ_ERROR_REPORT {
+            Co2ReadErrorReport::Null => {}
+            Co2ReadErrorReport::Omit if co2_read_failed => {
+                map.remove("co2_ppm");
+            }
+            Co2ReadErrorReport::Omit => {}
+            Co2ReadErrorReport::Sentinel => {
+                if co2_read_failed {
+                    map.insert("co2_ppm".to_string(), json!(CO2_ERROR_SENTINEL_PPM));
                 }
+                map.insert("co2_valid".to_string(), json!(!co2_read_failed));
+            }
+        }
+        if let (true, Some(raw)) = (CO2_REPORT_ADC_RAW, reading.co2_adc_raw) {
+            map.insert("co2_adc_raw".to_string(), json!(raw));
+        }
+        if let Some(chip_temperature) = reading.chip_temperature {
+            map.insert("chip_temperature".to_string(), json!(round_to(chip_temperature, TEMPERATURE_DECIMALS)));
+        }
+        map.extend(extra_values);
+    }
+    if WIFI_CONNECT_NON_BLOCKING && !mqtt_client.is_connected() {
+        boot_backlog.push(values);
+        return Ok(());
+    }
+    if let (Value::Object(map), Some(Value::Object(report))) = (&mut values, boot_backlog.take_drop_report()) {
+        map.extend(report);
+    }
+    if !TELEMETRY_SINKS.is_empty() {
+        publish_to_sinks(mqtt_client, &values, reading, time_sync);
+    }
+    if PUBLISH_ON_CHANGE_ENABLED && !change_filter.should_publish(&values) {
+        info!("Readings unchanged, telemetry skipped");
+        return Ok(());
+    }
+    if GATEWAY_MODE_ENABLED {
+        if let Err(e) = publish_gateway_telemetry(mqtt_client, &values, reading, time_sync) {
+            error!("Failed to publish gateway telemetry: {:?}", e);
+        }
+    }
+    if TELEMETRY_BATCH_SIZE > 1 {
+        // Without a wall clock the entry goes in without ts and is ordered by its uptime_ms
+        if SNTP_UPTIME_FALLBACK && !time_sync.has_synced() {
+            telemetry_batch.push(values);
+        } else {
+            telemetry_batch.push(json!({ "ts": reading.timestamp, "values": values }));
+        }
+        if telemetry_batch.is_due() {
+            telemetry_batch.flush(mqtt_client)?;
+        }
+        return Ok(());
+    }
+    publish_telemetry(mqtt_client, &values)?;
+    info!("Data sent to ThingsBoard: {}", values);
+    Ok(())
+}
+
+fn sensor_error_json(sensor: &str, detail: &str) -> Value {
+    json!({ "sensor_error": sensor, "sensor_error_detail": detail })
+}
+
+fn send_boot_telemetry(mqtt_client: &SimpleMqttClient, ota_manager: &OtaManager, i2c_devices: &[u8]) -> Result<()> {
+    let i2c_addresses: Vec<String> = i2c_devices.iter().map(|addr| format!("0x{:02x}", addr)).collect();
+    let mut payload = json!({
+        "device_name": device_name(),
+        "i2c_devices": i2c_addresses
+    });
+    if let (Value::Object(fields), Value::Object(identity)) = (&mut payload, ota_manager.firmware_identity_json()) {
+        fields.extend(identity);
+    }
+    let payload = payload.to_string();
+    mqtt_client.publish(OTA_TELEMETRY_TOPIC, &payload)?;
+    info!("Boot telemetry sent: {}", payload);
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum RpcMethod {
+    ListCommands,
+    GetBme280Config,
+    SetBme280Config,
+    BoostTelemetry,
+    SetLocation,
+    SetBroker,
+    ClearRebootCounter,
+    GetDiagnostics,
+}
+
+struct RpcCommand {
+    method: RpcMethod,
+    name: &'static str,
+    description: &'static str,
+    // Accepted "params" keys, with "?" marking optional ones
+    params: &'static [&'static str],
+}
+
+// Every supported RPC method. Requests are dispatched through this table, so listCommands always matches
+// what the device actually handles.
+const RPC_COMMANDS: &[RpcCommand] = &[
+    RpcCommand { method: RpcMethod::ListCommands, name: "listCommands", description: "List the supported RPC methods", params: &[] },
+    RpcCommand { method: RpcMethod::GetBme280Config, name: "getBme280Config", description: "Current BME280 oversampling and filter settings", params: &[] },
+    RpcCommand {
+        method: RpcMethod::SetBme280Config,
+        name: "setBme280Config",
+        description: "Change BME280 oversampling (1, 2, 4, 8, 16) and IIR filter (0, 2, 4, 8, 16)",
+        params: &["temperature_oversampling?", "pressure_oversampling?", "humidity_oversampling?", "iir_filter?"],
+    },
+    RpcCommand {
+        method: RpcMethod::BoostTelemetry,
+        name: "boostTelemetry",
+        description: "Sample at a shorter interval for a limited time",
+        params: &["seconds", "interval_ms"],
+    },
+    RpcCommand { method: RpcMethod::SetLocation, name: "setLocation", description: "Store the device coordinates", params: &["lat", "lon"] },
+    RpcCommand {
+        method: RpcMethod::SetBroker,
+        name: "setBroker",
+        description: "Switch to another MQTT broker after a reboot, reverting if it is unreachable",
+        params: &["url", "user", "pass"],
+    },
+    RpcCommand { method: RpcMethod::ClearRebootCounter, name: "clearRebootCounter", description: "Reset the lifetime reboot count", params: &[] },
+    RpcCommand { method: RpcMethod::GetDiagnostics, name: "getDiagnostics", description: "Firmware, connectivity, sensor and OTA status", params: &[] },
+];
+
+fn rpc_commands_json() -> Value {
+    let commands: Vec<Value> = RPC_COMMANDS.iter()
+        .map(|command| json!({ "method": command.name, "description": command.description, "params": command.params }))
+        .collect();
+    json!({ "commands": commands })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_rpc_request(
+    request: &RpcRequest,
+    sensor_hub: &SensorHub,
+    ota_manager: &OtaManager,
+    time_sync: &TimeSync,
+    telemetry_boost: &mut TelemetryBoost,
+    lifetime_stats: &mut LifetimeStats,
+    device_location: &mut DeviceLocation,
+    broker_config: &mut BrokerConfig
+) -> Result<Value> {
+    let command = RPC_COMMANDS.iter().find(|command| command.name == request.method)
+        .ok_or_else(|| anyhow!("Unknown RPC method: {}", request.method))?;
+    match command.method {
+        RpcMethod::ListCommands => Ok(rpc_commands_json()),
+        // Only the BME280 commands lock the sensors, so the rest keep working once they are released
+        RpcMethod::GetBme280Config => sensor_hub.with_state(|state| state.bme280_settings.to_json())
+            .ok_or_else(|| anyhow!("Sensors are not available")),
+        RpcMethod::SetBme280Config => {
+            let settings = sensor_hub.with_state(|state| {
+                let settings = state.bme280_settings.with_overrides(&request.params)?;
+                init_bme280(&mut state.bme280, &settings).map_err(|e| anyhow!("Failed to reconfigure BME280: {:?}", e))?;
+                state.bme280_settings = settings;
+                Ok::<_, anyhow::Error>(settings)
+            }).ok_or_else(|| anyhow!("Sensors are not available"))??;
+            info!("BME280 reconfigured: {}", settings.to_json());
+            Ok(settings.to_json())
+        }
+        RpcMethod::BoostTelemetry => telemetry_boost.start(&request.params),
+        RpcMethod::SetLocation => device_location.set(&request.params),
+        RpcMethod::SetBroker => broker_config.set(&request.params),
+        // Written through right away so a maintenance power cycle straight after does not bring the count back
+        RpcMethod::ClearRebootCounter => {
+            let previous = LifetimeCounter::Reboots.reset();
+            lifetime_stats.flush();
+            info!("Reboot counter cleared over RPC (was {})", previous);
+            Ok(json!({ "cleared": true, "previous_reboots": previous }))
+        }
+        RpcMethod::GetDiagnostics => {
+            let mut diagnostics = json!({
+                "uptime_ms": unsafe { esp_timer_get_time() } / 1000,
+                "free_heap": unsafe { esp_get_free_heap_size() },
+                "wifi_rssi": wifi_rssi(),
+                "wifi_ap": wifi_ap_json(),
+                "readings": sensor_hub.latest_readings().to_json(),
+                "time_sync": time_sync.to_json(),
+                "fw_state": ota_manager.ota_state_str(),
+                "fw_progress": ota_manager.progress_percent(),
+                "bme280": sensor_hub.with_state(|state| state.bme280_settings.to_json()),
+                "telemetry_boost": telemetry_boost.to_json(),
+                "lifetime_stats": lifetime_stats_json(),
+                "ota_events": &ota_manager.event_log.entries
+            });
+            if let (Value::Object(fields), Value::Object(identity)) = (&mut diagnostics, ota_manager.firmware_identity_json()) {
+                fields.extend(identity);
             }
+            Ok(diagnostics)
+        }
+    }
+}
+
+// A fault that stops the firmware during startup is published once the broker session is up, so an unattended
+// device reports why it went silent
+fn report_startup_failure(mqtt_client: &SimpleMqttClient, mqtt_context: &MqttContext, subsystem: &str, error: &anyhow::Error) {
+    let mut waited_ms = 0;
+    while !mqtt_context.is_connected() && waited_ms < STARTUP_FAILURE_REPORT_WAIT_MS {
+        unsafe { vTaskDelay(ms_to_ticks(100)); }
+        waited_ms += 100;
+    }
+    if !mqtt_context.is_connected() {
+        error!("MQTT not connected, startup failure was not reported");
+        return;
+    }
+    let payload = json!({
+        "health": HealthStatus::Critical.as_str(),
+        "health_issues": [subsystem],
+        "startup_error": format!("{:?}", error)
+    });
+    if let Err(e) = publish_telemetry(mqtt_client, &payload) {
+        error!("Failed to report startup failure: {:?}", e);
+    }
+    // Give the client task time to send it before the caller tears the client down
+    unsafe { vTaskDelay(ms_to_ticks(1000)); }
+}
+
+// Lowercase hex station MAC, unique per chip
+fn station_mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    let res = unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) };
+    if res != ESP_OK {
+        error!("Failed to read MAC address, error code: {}", res);
+    }
+    mac
+}
+
+fn device_name() -> String {
+    let suffix: String = station_mac().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", MQTT_CLIENT_ID_PREFIX, suffix)
+}
+
+#[allow(non_upper_case_globals)] // matches on bindgen constant names
+fn chip_model_name(model: esp_chip_model_t) -> String {
+    match model {
+        esp_chip_model_t_CHIP_ESP32 => "ESP32".to_string(),
+        esp_chip_model_t_CHIP_ESP32S2 => "ESP32-S2".to_string(),
+        esp_chip_model_t_CHIP_ESP32S3 => "ESP32-S3".to_string(),
+        esp_chip_model_t_CHIP_ESP32C3 => "ESP32-C3".to_string(),
+        other => format!("UNKNOWN({})", other),
+    }
+}
+
+fn partition_layout_json() -> Value {
+    let mut partitions = Vec::new();
+    unsafe {
+        let mut iterator = esp_partition_find(
+            esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            core::ptr::null()
+        );
+        while !iterator.is_null() {
+            let partition = esp_partition_get(iterator);
+            partitions.push(json!({
+                "label": CStr::from_ptr((*partition).label.as_ptr()).to_str().unwrap_or("unknown"),
+                "type": (*partition).type_,
+                "subtype": (*partition).subtype,
+                "address": format!("0x{:x}", (*partition).address),
+                "size": (*partition).size
+            }));
+            iterator = esp_partition_next(iterator);
+        }
+        esp_partition_iterator_release(iterator);
+    }
+    Value::Array(partitions)
+}
+
+fn send_device_info(mqtt_client: &SimpleMqttClient, ota_manager: &OtaManager) -> Result<()> {
+    let mut chip_info = esp_chip_info_t::default();
+    unsafe { esp_chip_info(&mut chip_info); }
+    let mut device_info = json!({
+        "device_name": device_name(),
+        "chip_model": chip_model_name(chip_info.model),
+        "chip_revision": chip_info.revision,
+        "chip_cores": chip_info.cores,
+        "mac": station_mac().iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"),
+        "partitions": partition_layout_json()
+    });
+    if let (Value::Object(fields), Value::Object(identity)) = (&mut device_info, ota_manager.firmware_identity_json()) {
+        fields.extend(identity);
+    }
+    let payload = json!({ "device_info": device_info }).to_string();
+    mqtt_client.publish_retained(DEVICE_INFO_TOPIC, &payload)?;
+    info!("Device info published: {}", payload);
+    Ok(())
+}
+
+fn mqtt_client_id() -> String {
+    match DEFAULT_CONFIG.mqtt_client_id {
+        Some(client_id) => client_id.to_string(),
+        None => device_name(),
+    }
+}
+
+// Record of the access point the station is associated with; None while disconnected
+fn wifi_ap_record() -> Option<wifi_ap_record_t> {
+    unsafe {
+        let mut ap_info: wifi_ap_record_t = Default::default();
+        if esp_wifi_sta_get_ap_info(&mut ap_info) == ESP_OK {
+            Some(ap_info)
+        } else {
+            None
+        }
+    }
+}
+
+fn wifi_rssi() -> Option<i8> {
+    wifi_ap_record().map(|ap_info| ap_info.rssi)
+}
+
+// (current RSSI, OTA_MIN_RSSI_DBM) when the link is too weak to start a download
+fn weak_signal() -> Option<(i8, i8)> {
+    let min_rssi = OTA_MIN_RSSI_DBM?;
+    wifi_rssi().filter(|&rssi| rssi < min_rssi).map(|rssi| (rssi, min_rssi))
+}
+
+#[allow(non_upper_case_globals)] // matches on bindgen constant names
+fn wifi_auth_mode_name(auth_mode: wifi_auth_mode_t) -> String {
+    match auth_mode {
+        wifi_auth_mode_t_WIFI_AUTH_OPEN => "OPEN".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WEP => "WEP".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WPA_PSK => "WPA_PSK".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WPA2_PSK => "WPA2_PSK".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WPA_WPA2_PSK => "WPA_WPA2_PSK".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK => "WPA3_PSK".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WPA2_WPA3_PSK => "WPA2_WPA3_PSK".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_WAPI_PSK => "WAPI_PSK".to_string(),
+        wifi_auth_mode_t_WIFI_AUTH_OWE => "OWE".to_string(),
+        other => format!("UNKNOWN({})", other),
+    }
+}
+
+// Which AP and channel the station is on, to spot a device stuck on a weak or wrong AP
+fn wifi_ap_json() -> Value {
+    match wifi_ap_record() {
+        Some(ap_info) => json!({
+            "wifi_channel": ap_info.primary,
+            "bssid": ap_info.bssid.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"),
+            "auth_mode": wifi_auth_mode_name(ap_info.authmode)
+        }),
+        None => json!({
+            "wifi_channel": null,
+            "bssid": null,
+            "auth_mode": null
+        }),
+    }
+}
+
+#[cfg(feature = "http-status")]
+struct StatusContext {
+    sensor_hub: &'static SensorHub,
+    ota_status: &'static Guarded<OtaStatusSnapshot>,
+}
+
+#[cfg(feature = "http-status")]
+struct StatusServer {
+    handle: httpd_handle_t,
+    _context: Box<StatusContext>,
+}
 
-            self.received_size += data.len();
-            info!("Received chunk {}, size: {}, total received: {}", chunk_index, data.len(), self.received_size);
-            
-            if let Some(fw_size) = self.fw_size {
-                let percentage = (self.received_size as f32 / fw_size as f32) * 100.0;
-                info!("Download progress: {:.2}% ({} / {})", percentage, self.received_size, fw_size);
+#[cfg(feature = "http-status")]
+impl StatusServer {
+    fn start(sensor_hub: &'static SensorHub, ota_status: &'static Guarded<OtaStatusSnapshot>) -> Result<Self> {
+        let mut context = Box::new(StatusContext { sensor_hub, ota_status });
+        unsafe {
+            // Mirrors HTTPD_DEFAULT_CONFIG(), which is a C macro and not part of the bindings
+            let config = httpd_config_t {
+                task_priority: 5,
+                stack_size: 6144,
+                core_id: i32::MAX,
+                server_port: STATUS_SERVER_PORT,
+                ctrl_port: 32768,
+                max_open_sockets: 4,
+                max_uri_handlers: 4,
+                max_resp_headers: 8,
+                backlog_conn: 5,
+                recv_wait_timeout: 5,
+                send_wait_timeout: 5,
+                ..Default::default()
+            };
+            let mut handle: httpd_handle_t = core::ptr::null_mut();
+            let res = httpd_start(&mut handle, &config);
+            if res != ESP_OK {
+                return Err(anyhow!("Failed to start HTTP server: {}", res));
             }
-            
-            self.sha256_hasher.update(data);
-            unsafe {
-                let res = esp_ota_write(self.ota_handle, data.as_ptr() as *const c_void, data.len());
-                if res != ESP_OK {
-                    self.ota_state = OtaState::Failed(format!("Failed to write OTA data: {}", res));
-                    self.send_ota_telemetry(mqtt_client)?;
-                    return Err(anyhow!("Failed to write OTA data: {}", res));
-                }
+            let uri = httpd_uri_t {
+                uri: c"/status".as_ptr(),
+                method: http_method_HTTP_GET,
+                handler: Some(Self::status_handler),
+                user_ctx: &mut *context as *mut StatusContext as *mut c_void,
+                ..Default::default()
+            };
+            let res = httpd_register_uri_handler(handle, &uri);
+            if res != ESP_OK {
+                httpd_stop(handle);
+                return Err(anyhow!("Failed to register /status handler: {}", res));
             }
+            info!("HTTP status page available on port {} at /status", STATUS_SERVER_PORT);
+            Ok(Self { handle, _context: context })
+        }
+    }
 
-            self.current_chunk += 1;
-            self.last_chunk_received = unsafe { xTaskGetTickCount() };
-            if let Some(fw_size) = self.fw_size {
-                if self.received_size >= fw_size as usize {
-                    self.ota_state = OtaState::Downloaded;
-                    unsafe {
-                        let res = esp_ota_end(self.ota_handle);
-                        if res != ESP_OK {
-                            self.ota_state = OtaState::Failed(format!("Failed to end OTA: {}", res));
-                            self.send_ota_telemetry(mqtt_client)?;
-                            return Err(anyhow!("Failed to end OTA: {}", res));
-                        }
-                    }
-                    self.process_firmware(mqtt_client)?;
-                } else {
-                    self.process_buffered_chunks(mqtt_client)?;
-                    self.request_firmware_chunk(mqtt_client, self.current_chunk)?;
-                }
-            }
-            Ok(())
-        } else {
-            info!("Received out-of-order chunk {}, storing in buffer", chunk_index);
-            self.chunk_buffer.push((chunk_index, data.to_vec()));
-            self.chunk_buffer.sort_by_key(|&(index, _)| index);
-            self.process_buffered_chunks(mqtt_client)?;
-            Ok(())
+    unsafe extern "C" fn status_handler(req: *mut httpd_req_t) -> esp_err_t {
+        let context = &*((*req).user_ctx as *const StatusContext);
+        let latest_readings = context.sensor_hub.latest_readings();
+        let ota_status = context.ota_status.with(|status| status.clone());
+        let mut body = json!({
+            "readings": latest_readings.to_json(),
+            "wifi_rssi": wifi_rssi(),
+            "fw_state": ota_status.fw_state,
+            "fw_progress": ota_status.fw_progress,
+            "uptime_ms": esp_timer_get_time() / 1000
+        });
+        if let (Value::Object(fields), Value::Object(identity)) = (&mut body, ota_status.firmware_identity) {
+            fields.extend(identity);
         }
+        let body = body.to_string();
+        httpd_resp_set_type(req, c"application/json".as_ptr());
+        httpd_resp_send(req, body.as_ptr() as *const c_char, body.len() as _)
     }
+}
 
-    fn process_buffered_chunks(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        while let Some(index) = self.chunk_buffer.iter().position(|&(index, _)| index == self.current_chunk) {
-            let (_, data) = self.chunk_buffer.remove(index);
-            self.handle_firmware_chunk(&data, self.current_chunk, mqtt_client)?;
+#[cfg(feature = "http-status")]
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        unsafe {
+            httpd_stop(self.handle);
         }
-        Ok(())
     }
+}
 
-    fn process_firmware(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        self.ota_state = OtaState::Verifying;
-        self.send_ota_telemetry(mqtt_client)?;
+// Clocks SCL until a slave stuck mid-byte lets go of SDA, then issues a STOP. Returns whether SDA ended up high.
+// The I2C driver must not be installed while this runs.
+fn release_i2c_bus() -> bool {
+    unsafe {
+        for pin in [DEFAULT_CONFIG.i2c_sda_gpio, DEFAULT_CONFIG.i2c_scl_gpio] {
+            gpio_reset_pin(pin);
+            gpio_set_pull_mode(pin, gpio_pull_mode_t_GPIO_PULLUP_ONLY);
+            gpio_set_level(pin, 1);
+            gpio_set_direction(pin, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD);
+        }
+        esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
 
-        if let Some(checksum) = &self.fw_checksum {
-            let computed_checksum = {
-                let result = self.sha256_hasher.clone().finalize();
-                result.iter().map(|b| format!("{:02x}", b)).collect::<String>()
-            };
-            info!("Computed checksum: {}, Expected checksum: {}", computed_checksum, checksum);
-            if computed_checksum == *checksum {
-                self.ota_state = OtaState::Updating;
-                self.send_ota_telemetry(mqtt_client)?;
-                unsafe {
-                    let res = esp_ota_set_boot_partition(self.ota_partition);
-                    if res != ESP_OK {
-                        self.ota_state = OtaState::Failed(format!("Failed to set boot partition: {}", res));
-                        self.send_ota_telemetry(mqtt_client)?;
-                        return Err(anyhow!("Failed to set boot partition: {}", res));
-                    }
-                }
-                self.current_fw_title = self.fw_title.clone().unwrap_or_default();
-                self.current_fw_version = self.fw_version.clone().unwrap_or_default();
-                self.ota_state = OtaState::Updated;
-                self.send_ota_telemetry(mqtt_client)?;
-                info!("Firmware update successful, restarting...");
-                unsafe { esp_restart(); }
-            } else {
-                self.ota_state = OtaState::Failed("Checksum verification failed".to_string());
-                self.send_ota_telemetry(mqtt_client)?;
-                return Err(anyhow!("Checksum verification failed"));
-            }
-        } else {
-            self.ota_state = OtaState::Failed("No checksum provided".to_string());
-            self.send_ota_telemetry(mqtt_client)?;
-            return Err(anyhow!("No checksum provided"));
+        let mut pulses = 0;
+        while gpio_get_level(DEFAULT_CONFIG.i2c_sda_gpio) == 0 && pulses < I2C_RECOVERY_CLOCK_PULSES {
+            gpio_set_level(DEFAULT_CONFIG.i2c_scl_gpio, 0);
+            esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+            gpio_set_level(DEFAULT_CONFIG.i2c_scl_gpio, 1);
+            esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+            pulses += 1;
+        }
+        info!("I2C bus recovery: {} SCL pulses", pulses);
+
+        // STOP condition: SDA rises while SCL is high
+        gpio_set_level(DEFAULT_CONFIG.i2c_scl_gpio, 0);
+        esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(DEFAULT_CONFIG.i2c_sda_gpio, 0);
+        esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(DEFAULT_CONFIG.i2c_scl_gpio, 1);
+        esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(DEFAULT_CONFIG.i2c_sda_gpio, 1);
+        esp_rom_delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+
+        let released = gpio_get_level(DEFAULT_CONFIG.i2c_sda_gpio) != 0;
+        for pin in [DEFAULT_CONFIG.i2c_sda_gpio, DEFAULT_CONFIG.i2c_scl_gpio] {
+            gpio_reset_pin(pin);
         }
+        released
     }
+}
 
-    fn send_ota_telemetry(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        self.telemetry_counter += 1;
-        if self.ota_state == OtaState::Downloading && self.telemetry_counter < ms_to_ticks(5000) / ms_to_ticks(100) {
-            return Ok(());
+fn reinstall_i2c_driver() -> Result<I2cDriver<'static>> {
+    // The previous driver has been dropped, so taking the peripheral and pins again does not alias them
+    let (i2c0, sda, scl) = unsafe {
+        (I2C0::new(), AnyIOPin::new(DEFAULT_CONFIG.i2c_sda_gpio), AnyIOPin::new(DEFAULT_CONFIG.i2c_scl_gpio))
+    };
+    I2cDriver::new(i2c0, sda, scl, &esp_idf_hal::i2c::I2cConfig::new().baudrate(DEFAULT_CONFIG.i2c_baudrate_khz.kHz().into()))
+        .map_err(|e| anyhow!("{:?}", e))
+}
+
+fn scan_i2c_bus(i2c: &mut I2cDriver<'_>) -> Vec<u8> {
+    let mut found = Vec::new();
+    for addr in I2C_SCAN_FIRST_ADDR..=I2C_SCAN_LAST_ADDR {
+        if i2c.write(addr, &[], ms_to_ticks(10)).is_ok() {
+            info!("I2C device found at 0x{:02x}", addr);
+            found.push(addr);
         }
-        self.telemetry_counter = 0;
-        let payload = match &self.ota_state {
-            OtaState::Idle => json!({
-                "current_fw_title": &self.current_fw_title,
-                "current_fw_version": &self.current_fw_version,
-                FW_STATE_ATTR: "IDLE"
-            }).to_string(),
-            OtaState::Downloading => json!({
-                "current_fw_title": &self.current_fw_title,
-                "current_fw_version": &self.current_fw_version,
-                FW_STATE_ATTR: "DOWNLOADING",
-                "progress": if let Some(fw_size) = self.fw_size { self.received_size as f32 / fw_size as f32 * 100.0 } else { 0.0 }
-            }).to_string(),
-            OtaState::Downloaded => json!({
-                "current_fw_title": &self.current_fw_title,
-                "current_fw_version": &self.current_fw_version,
-                FW_STATE_ATTR: "DOWNLOADED"
-            }).to_string(),
-            OtaState::Verifying => json!({
-                "current_fw_title": &self.current_fw_title,
-                "current_fw_version": &self.current_fw_version,
-                FW_STATE_ATTR: "VERIFYING"
-            }).to_string(),
-            OtaState::Updating => json!({
-                "current_fw_title": &self.current_fw_title,
-                "current_fw_version": &self.current_fw_version,
-                FW_STATE_ATTR: "UPDATING"
-            }).to_string(),
-            OtaState::Updated => json!({
-                "current_fw_title": &self.current_fw_title,
-                "current_fw_version": &self.current_fw_version,
-                FW_STATE_ATTR: "UPDATED"
-            }).to_string(),
-            OtaState::Failed(error) => json!({
-                FW_STATE_ATTR: "FAILED",
-                "fw_error": error
-            }).to_string(),
+    }
+    if found.is_empty() {
+        error!("I2C scan found no devices, check SDA/SCL wiring and sensor power");
+    }
+    let bme280_report: Vec<String> = BME280_ADDRESSES.iter().map(|addr| {
+        if found.contains(addr) {
+            format!("found device at 0x{:02x}", addr)
+        } else {
+            format!("no device found at 0x{:02x}", addr)
+        }
+    }).collect();
+    info!("BME280 probe: {}", bme280_report.join("; "));
+    found
+}
+
+// Owns the SNTP client for the lifetime of the firmware and tracks how fresh the wall clock is
+struct TimeSync {
+    sntp: Option<EspSntp<'static>>,
+    last_sync_uptime_ms: Option<i64>,
+    last_resync_request_ms: i64,
+}
+
+impl TimeSync {
+    fn start() -> Self {
+        let sntp = match EspSntp::new_default() {
+            Ok(sntp) => {
+                info!("SNTP initialized");
+                Some(sntp)
+            }
+            Err(e) => {
+                error!("Failed to initialize SNTP: {:?}", e);
+                None
+            }
         };
-        Self::mqtt_publish(mqtt_client, OTA_TELEMETRY_TOPIC, &payload)?;
-        info!("Sent OTA telemetry: {}", payload);
-        Ok(())
+        Self { sntp, last_sync_uptime_ms: None, last_resync_request_ms: 0 }
     }
 
-    fn check_chunk_timeout(&mut self, mqtt_client: *mut esp_mqtt_client) -> Result<()> {
-        if self.ota_state == OtaState::Downloading {
-            let current_ticks = unsafe { xTaskGetTickCount() };
-            if current_ticks - self.last_chunk_received > ms_to_ticks(10000) {
-                info!("No chunks received for 10 seconds, re-requesting chunk {}", self.current_chunk);
-                self.request_firmware_chunk(mqtt_client, self.current_chunk)?;
-                self.last_chunk_received = current_ticks;
+    fn wait_for_sync(&mut self, timeout_s: u32) -> Result<()> {
+        info!("Waiting for SNTP sync...");
+        for _ in 0..timeout_s {
+            self.poll();
+            if self.last_sync_uptime_ms.is_some() {
+                return Ok(());
             }
+            unsafe { vTaskDelay(ms_to_ticks(1000)); }
         }
-        Ok(())
+        Err(anyhow!("SNTP sync timed out"))
     }
 
-    fn mqtt_publish(mqtt_client: *mut esp_mqtt_client, topic: &str, data: &str) -> Result<()> {
-        unsafe {
-            let topic_cstr = CString::new(topic)?;
-            let data_cstr = CString::new(data)?;
-            let msg_id = esp_mqtt_client_publish(
-                mqtt_client,
-                topic_cstr.as_ptr(),
-                data_cstr.as_ptr(),
-                data.len() as i32,
-                1,
-                0
-            );
-            if msg_id < 0 {
-                Err(anyhow!("Failed to publish message to {}: {}", topic, msg_id))
-            } else {
-                info!("Published message to {} with ID: {}", topic, msg_id);
-                Ok(())
-            }
+    fn poll(&mut self) {
+        let Some(sntp) = self.sntp.as_ref() else {
+            return;
+        };
+        let now_ms = unsafe { esp_timer_get_time() } / 1000;
+        // The status reads Completed once per finished sync and then resets
+        if sntp.get_sync_status() == SyncStatus::Completed {
+            info!("SNTP sync completed");
+            self.last_sync_uptime_ms = Some(now_ms);
         }
+        let since_sync = now_ms - self.last_sync_uptime_ms.unwrap_or(0);
+        if since_sync >= SNTP_RESYNC_INTERVAL_MS && now_ms - self.last_resync_request_ms >= SNTP_RESYNC_INTERVAL_MS {
+            info!("Requesting SNTP re-sync, last sync {} s ago", since_sync / 1000);
+            self.last_resync_request_ms = now_ms;
+            unsafe { esp_sntp_restart(); }
+        }
+    }
+
+    fn has_synced(&self) -> bool {
+        self.last_sync_uptime_ms.is_some()
+    }
+
+    fn time_source(&self) -> &'static str {
+        if self.has_synced() { "ntp" } else { "uptime" }
+    }
+
+    fn is_reliable(&self) -> bool {
+        let now_ms = unsafe { esp_timer_get_time() } / 1000;
+        matches!(self.last_sync_uptime_ms, Some(last_sync) if now_ms - last_sync < SNTP_STALE_AFTER_MS)
+    }
+
+    fn to_json(&self) -> Value {
+        let now_ms = unsafe { esp_timer_get_time() } / 1000;
+        json!({
+            "running": self.sntp.is_some(),
+            "synced": self.last_sync_uptime_ms.is_some(),
+            "seconds_since_sync": self.last_sync_uptime_ms.map(|last_sync| (now_ms - last_sync) / 1000),
+            "reliable": self.is_reliable()
+        })
     }
 }
 
-struct SimpleMqttClient {
-    client: *mut esp_mqtt_client,
+fn wifi_credentials() -> Result<(heapless::String<WIFI_SSID_MAX_BYTES>, heapless::String<WIFI_PASSWORD_MAX_BYTES>)> {
+    let ssid = heapless::String::try_from(DEFAULT_CONFIG.wifi_ssid)
+        .map_err(|_| anyhow!("SSID too long: {} bytes, max {} bytes", DEFAULT_CONFIG.wifi_ssid.len(), WIFI_SSID_MAX_BYTES))?;
+    let password = heapless::String::try_from(DEFAULT_CONFIG.wifi_password)
+        .map_err(|_| anyhow!("WiFi password too long: {} bytes, max {} bytes", DEFAULT_CONFIG.wifi_password.len(), WIFI_PASSWORD_MAX_BYTES))?;
+    Ok((ssid, password))
 }
 
-impl SimpleMqttClient {
-    fn new(broker_url: &str, username: &str, password: &str, client_id: &str, ota_manager_ptr: *mut OtaManager) -> Result<Self> {
-        unsafe {
-            let broker_url_cstr = CString::new(broker_url)?;
-            let username_cstr = CString::new(username)?;
-            let password_cstr = CString::new(password)?;
-            let client_id_cstr = CString::new(client_id)?;
-            let config = esp_mqtt_client_config_t {
-                broker: esp_mqtt_client_config_t_broker_t {
-                    address: esp_mqtt_client_config_t_broker_t_address_t {
-                        uri: broker_url_cstr.as_ptr(),
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                },
-                credentials: esp_mqtt_client_config_t_credentials_t {
-                    username: username_cstr.as_ptr(),
-                    client_id: client_id_cstr.as_ptr(),
-                    authentication: esp_mqtt_client_config_t_credentials_t_authentication_t {
-                        password: password_cstr.as_ptr(),
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                },
-                buffer: esp_mqtt_client_config_t_buffer_t {
-                    size: 8192,
-                    out_size: 8192,
-                    ..Default::default()
-                },
-                ..Default::default()
-            };
-            let client = esp_mqtt_client_init(&config);
-            if client.is_null() {
-                return Err(anyhow!("Failed to initialize MQTT client"));
+fn configure_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
+    let (ssid, password) = wifi_credentials()?;
+    let wifi_config = Configuration::Client(ClientConfiguration {
+        ssid,
+        password,
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    });
+    wifi.set_configuration(&wifi_config)?;
+    if !wifi.is_started()? {
+        wifi.start()?;
+    }
+    Ok(())
+}
+
+fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
+    configure_wifi(wifi)?;
+    wifi.connect()?;
+    wait_netif_up(wifi)?;
+    let ip_info: IpInfo = wifi.wifi().sta_netif().get_ip_info()?;
+    info!("WiFi Connected, IP: {}", ip_info.ip);
+    Ok(())
+}
+
+// Kicks off association and returns immediately; the driver brings the netif up in the background
+fn start_wifi_connect(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
+    configure_wifi(wifi)?;
+    wifi.wifi_mut().connect()?;
+    info!("WiFi connecting in the background");
+    Ok(())
+}
+
+struct ConnectivityMonitor {
+    ping: EspPing,
+    last_check_tick: u32,
+    consecutive_failures: u32,
+    last_rtt_ms: Option<u32>,
+}
+
+impl ConnectivityMonitor {
+    fn new() -> Self {
+        Self { ping: EspPing::default(), last_check_tick: unsafe { xTaskGetTickCount() }, consecutive_failures: 0, last_rtt_ms: None }
+    }
+
+    fn check_due(&self) -> bool {
+        let now = unsafe { xTaskGetTickCount() };
+        ticks_elapsed(now, self.last_check_tick).unwrap_or(u32::MAX) >= ms_to_ticks(CONNECTIVITY_CHECK_INTERVAL_MS)
+    }
+
+    // Pings the target and returns true when at least one reply came back
+    fn check(&mut self, wifi: &BlockingWifi<EspWifi<'static>>) -> bool {
+        self.last_check_tick = unsafe { xTaskGetTickCount() };
+        let result = self.ping_target(wifi);
+        match &result {
+            Ok(rtt_ms) => {
+                if self.consecutive_failures > 0 {
+                    info!("Connectivity restored after {} failed checks", self.consecutive_failures);
+                }
+                self.consecutive_failures = 0;
+                self.last_rtt_ms = Some(*rtt_ms);
             }
-            esp_mqtt_client_register_event(
-                client,
-                esp_mqtt_event_id_t_MQTT_EVENT_ANY,
-                Some(Self::mqtt_event_handler),
-                ota_manager_ptr as *mut c_void
-            );
-            let err = esp_mqtt_client_start(client);
-            if err != ESP_OK {
-                esp_mqtt_client_destroy(client);
-                return Err(anyhow!("Failed to start MQTT client, error code: {}", err));
+            Err(e) => {
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+                self.last_rtt_ms = None;
+                error!("Connectivity check failed ({} in a row): {:?}", self.consecutive_failures, e);
             }
-            vTaskDelay(ms_to_ticks(5000));
-            info!("MQTT client started, verifying subscriptions...");
-            Ok(Self { client })
         }
+        result.is_ok()
     }
 
-    extern "C" fn mqtt_event_handler(
-        handler_args: *mut c_void,
-        _base: *const u8,
-        event_id: i32,
-        event_data: *mut c_void
-    ) {
-        unsafe {
-            let ota_manager = handler_args as *mut OtaManager;
-            if ota_manager.is_null() {
-                error!("OTA manager pointer is null");
-                return;
-            }
-            let event = &*(event_data as *mut esp_mqtt_event_t);
-            info!("MQTT event received, event_id: {}", event_id);
-            match event_id {
-                id if id == esp_mqtt_event_id_t_MQTT_EVENT_CONNECTED as i32 => {
-                    info!("MQTT connected to broker");
-                }
-                id if id == esp_mqtt_event_id_t_MQTT_EVENT_DISCONNECTED as i32 => {
-                    error!("MQTT disconnected from broker");
-                }
-                id if id == esp_mqtt_event_id_t_MQTT_EVENT_SUBSCRIBED as i32 => {
-                    let topic_len = event.topic_len as usize;
-                    let topic = if topic_len > 0 {
-                        let topic_slice = core::slice::from_raw_parts(event.topic as *const u8, topic_len);
-                        core::str::from_utf8(topic_slice).unwrap_or("unknown")
-                    } else {
-                        "unknown"
-                    };
-                    info!("Subscribed to topic: {}", topic);
-                }
-                id if id == esp_mqtt_event_id_t_MQTT_EVENT_DATA as i32 => {
-                    let topic_len = event.topic_len as usize;
-                    let data_len = event.data_len as usize;
-                    if topic_len > 0 && data_len > 0 {
-                        let topic_slice = core::slice::from_raw_parts(event.topic as *const u8, topic_len);
-                        let topic = core::str::from_utf8(topic_slice).unwrap_or("");
-                        info!("Received MQTT message on topic: {}, data_len: {}", topic, data_len);
-                        let data_slice = core::slice::from_raw_parts(event.data as *const u8, data_len);
-                        if topic.starts_with(OTA_RESPONSE_TOPIC) {
-                            if let Ok(data_str) = core::str::from_utf8(data_slice) {
-                                info!("OTA response data: {}", data_str);
-                                if let Err(e) = (*ota_manager).handle_shared_attributes(data_str, event.client) {
-                                    error!("Failed to handle OTA attributes: {:?}", e);
-                                }
-                            } else {
-                                error!("Invalid UTF-8 in OTA response");
-                            }
-                        } else if topic.starts_with(&format!("{}/{}/", OTA_FIRMWARE_RESPONSE_TOPIC, (*ota_manager).firmware_request_id)) {
-                            let total_len = event.total_data_len as usize;
-                            let offset = event.current_data_offset as usize;
-                            let chunk_data_len = event.data_len as usize;
-                            let data_slice = core::slice::from_raw_parts(event.data as *const u8, chunk_data_len);
-
-                            if offset == 0 {
-                                (*ota_manager).partial_firmware_data.clear();
-                                (*ota_manager).partial_firmware_data.extend_from_slice(data_slice);
-                            } else {
-                                (*ota_manager).partial_firmware_data.extend_from_slice(data_slice);
-                            }
+    fn ping_target(&mut self, wifi: &BlockingWifi<EspWifi<'static>>) -> Result<u32> {
+        let target = match CONNECTIVITY_CHECK_TARGET {
+            Some(target) => target,
+            None => wifi.wifi().sta_netif().get_ip_info()?.subnet.gateway,
+        };
+        let config = PingConfiguration {
+            count: CONNECTIVITY_CHECK_PINGS,
+            timeout: core::time::Duration::from_millis(CONNECTIVITY_CHECK_TIMEOUT_MS),
+            ..Default::default()
+        };
+        let summary = self.ping.ping(target, &config)?;
+        if summary.received == 0 {
+            return Err(anyhow!("No reply from {} to {} pings", target, summary.transmitted));
+        }
+        Ok(summary.time.as_millis() as u32 / summary.received)
+    }
 
-                            if offset + chunk_data_len >= total_len {
-                                let topic_parts: Vec<&str> = topic.split('/').collect();
-                                if let Some(chunk_str) = topic_parts.last() {
-                                    if let Ok(chunk_index) = chunk_str.parse::<u32>() {
-                                        info!("Received complete firmware chunk for request ID: {}, chunk: {}, data length: {}", 
-                                            (*ota_manager).firmware_request_id, chunk_index, (*ota_manager).partial_firmware_data.len());
-                                        (*ota_manager).last_chunk_received = xTaskGetTickCount();
-                                        if let Err(e) = (*ota_manager).handle_firmware_chunk(&(*ota_manager).partial_firmware_data, chunk_index, event.client) {
-                                            error!("Failed to handle firmware chunk: {:?}", e);
-                                        }
-                                    } else {
-                                        error!("Invalid chunk index in topic: {}", topic);
-                                    }
-                                }
-                                (*ota_manager).partial_firmware_data.clear();
-                            }
-                        } else {
-                            info!("Received MQTT message on unexpected topic: {}", topic);
-                        }
-                    }
-                }
-                _ => {
-                    info!("Unhandled MQTT event, event_id: {}", event_id);
-                }
-            }
+    fn reconnect_due(&self) -> bool {
+        self.consecutive_failures >= CONNECTIVITY_FAILURES_BEFORE_RECONNECT
+    }
+
+    fn to_json(&self) -> Value {
+        let mut status = json!({
+            "connectivity_ok": self.consecutive_failures == 0,
+            "connectivity_failures": self.consecutive_failures,
+            "connectivity_rtt_ms": self.last_rtt_ms
+        });
+        if let (Value::Object(fields), Value::Object(ap)) = (&mut status, wifi_ap_json()) {
+            fields.extend(ap);
         }
+        status
     }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum PublishRecovery {
+    None,
+    ReconnectMqtt,
+    ReconnectWifi,
+    Reboot,
+}
+
+// Tracks how far recovery has escalated during the current publish failure streak
+struct PublishFailureEscalation {
+    level: PublishRecovery,
+}
 
-    fn publish(&self, topic: &str, data: &str) -> Result<()> {
-        OtaManager::mqtt_publish(self.client, topic, data)
+impl PublishFailureEscalation {
+    fn new() -> Self {
+        Self { level: PublishRecovery::None }
     }
 
-    fn subscribe(&self, topic: &str) -> Result<()> {
-        unsafe {
-            let topic_cstr = CString::new(topic)?;
-            let result = esp_mqtt_client_subscribe_single(
-                self.client,
-                topic_cstr.as_ptr(),
-                1
-            );
-            if result == -1 {
-                error!("Failed to subscribe to topic: {}, retrying...", topic);
-                vTaskDelay(ms_to_ticks(1000));
-                let retry_result = esp_mqtt_client_subscribe_single(
-                    self.client,
-                    topic_cstr.as_ptr(),
-                    1
-                );
-                if retry_result == -1 {
-                    Err(anyhow!("Failed to subscribe to topic after retry: {}", topic))
-                } else {
-                    info!("Subscribed to topic after retry: {}", topic);
-                    Ok(())
-                }
-            } else {
-                info!("Subscribed to topic: {}", topic);
-                Ok(())
-            }
+    // Returns the next recovery step once the failure count crosses its threshold
+    fn poll(&mut self) -> PublishRecovery {
+        let failures = TELEMETRY_PUBLISH_FAILURES.load(Ordering::Relaxed);
+        let reached = |threshold: u32| threshold > 0 && failures >= threshold;
+        let target = if reached(PUBLISH_FAILURES_BEFORE_REBOOT) {
+            PublishRecovery::Reboot
+        } else if reached(PUBLISH_FAILURES_BEFORE_WIFI_RECONNECT) {
+            PublishRecovery::ReconnectWifi
+        } else if reached(PUBLISH_FAILURES_BEFORE_MQTT_RECONNECT) {
+            PublishRecovery::ReconnectMqtt
+        } else {
+            PublishRecovery::None
+        };
+        if failures == 0 {
+            self.level = PublishRecovery::None;
         }
+        if target <= self.level {
+            return PublishRecovery::None;
+        }
+        self.level = target;
+        error!("{} consecutive telemetry publishes failed, escalating recovery", failures);
+        target
     }
 }
 
-impl Drop for SimpleMqttClient {
-    fn drop(&mut self) {
-        unsafe {
-            esp_mqtt_client_stop(self.client);
-            esp_mqtt_client_destroy(self.client);
+fn wait_netif_up(wifi: &BlockingWifi<EspWifi<'static>>) -> Result<()> {
+    let start = unsafe { xTaskGetTickCount() };
+    let mut last_progress_log = start;
+    loop {
+        if wifi.is_up()? {
+            return Ok(());
+        }
+        let now = unsafe { xTaskGetTickCount() };
+        let elapsed = ticks_elapsed(now, start).unwrap_or(u32::MAX);
+        if elapsed >= ms_to_ticks(WIFI_NETIF_UP_TIMEOUT_MS) {
+            return Err(anyhow!("Timed out after {} ms waiting for an IP address (DHCP did not complete)", WIFI_NETIF_UP_TIMEOUT_MS));
         }
+        if ticks_elapsed(now, last_progress_log).unwrap_or(u32::MAX) >= ms_to_ticks(WIFI_PROGRESS_LOG_INTERVAL_MS) {
+            info!("Still waiting for an IP address... {} s elapsed", elapsed / ms_to_ticks(1000));
+            last_progress_log = now;
+        }
+        unsafe { vTaskDelay(ms_to_ticks(250)); }
     }
 }
 
-fn send_telemetry(
-    mqtt_client: &SimpleMqttClient,
-    temperature: f32,
-    humidity: f32,
-    pressure: f32,
-    co2_ppm: f32
-) -> Result<()> {
-    let payload = json!({
-        "temperature": temperature,
-        "humidity": humidity,
-        "pressure": pressure / 100.0,
-        "co2_ppm": co2_ppm,
-        "latitude": -7.278306,
-        "longitude": 112.792028
-    }).to_string();
-    mqtt_client.publish(OTA_TELEMETRY_TOPIC, &payload)?;
-    info!("Data sent to ThingsBoard: {}", payload);
-    Ok(())
+// Every problem found in the build-time configuration
+#[derive(Debug)]
+struct ConfigError {
+    problems: Vec<String>,
 }
 
-fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
-    let ssid = "GRATIS";
-    let password = "Gakgratis";
-    let wifi_config = Configuration::Client(ClientConfiguration {
-        ssid: heapless::String::try_from(ssid).unwrap(),
-        password: heapless::String::try_from(password).unwrap(),
-        auth_method: AuthMethod::WPA2Personal,
-        ..Default::default()
-    });
-    wifi.set_configuration(&wifi_config)?;
-    wifi.start()?;
-    wifi.connect()?;
-    wifi.wait_netif_up()?;
-    let ip_info: IpInfo = wifi.wifi().sta_netif().get_ip_info()?;
-    info!("WiFi Connected, IP: {}", ip_info.ip);
-    Ok(())
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} configuration problem(s): {}", self.problems.len(), self.problems.join("; "))
+    }
+}
+
+// ESP32-S3 GPIOs; 22 to 25 are not bonded out
+fn is_valid_gpio(pin: gpio_num_t) -> bool {
+    matches!(pin, 0..=21 | 26..=48)
+}
+
+impl DeviceConfig {
+    // Checks the site settings and the constants that have to agree with them. There are no per-field defaults
+    // to fall back to, since this is the default, so any problem stops the boot with all of them listed.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(!self.wifi_ssid.is_empty() && self.wifi_ssid.len() <= WIFI_SSID_MAX_BYTES,
+            format!("wifi_ssid must be 1 to {} bytes", WIFI_SSID_MAX_BYTES));
+        check(self.wifi_password.len() <= WIFI_PASSWORD_MAX_BYTES,
+            format!("wifi_password must be at most {} bytes", WIFI_PASSWORD_MAX_BYTES));
+        let broker = json!({ "url": self.mqtt_broker_url, "user": self.mqtt_username, "pass": self.mqtt_password });
+        if let Err(e) = BrokerSettings::from_params(&broker) {
+            check(false, format!("broker settings: {}", e));
+        }
+        check(!self.mqtt_client_id.is_some_and(str::is_empty), "mqtt_client_id must not be empty".to_string());
+        check((-90.0..=90.0).contains(&self.latitude), format!("latitude {} is outside -90..90", self.latitude));
+        check((-180.0..=180.0).contains(&self.longitude), format!("longitude {} is outside -180..180", self.longitude));
+        check(self.sensor_sample_interval_ms >= TELEMETRY_BOOST_MIN_INTERVAL_MS,
+            format!("sensor_sample_interval_ms must be at least {} ms", TELEMETRY_BOOST_MIN_INTERVAL_MS));
+        check(self.firmware_info_poll_interval_ms > 0, "firmware_info_poll_interval_ms must be positive".to_string());
+        check(is_valid_gpio(self.i2c_sda_gpio), format!("i2c_sda_gpio {} is not an ESP32-S3 GPIO", self.i2c_sda_gpio));
+        check(is_valid_gpio(self.i2c_scl_gpio), format!("i2c_scl_gpio {} is not an ESP32-S3 GPIO", self.i2c_scl_gpio));
+        check(self.i2c_sda_gpio != self.i2c_scl_gpio, "i2c_sda_gpio and i2c_scl_gpio must differ".to_string());
+        check((1..=1000).contains(&self.i2c_baudrate_khz), format!("i2c_baudrate_khz {} is outside 1..1000", self.i2c_baudrate_khz));
+        check(self.co2_adc_channel <= adc_channel_t_ADC_CHANNEL_9,
+            format!("co2_adc_channel {} is not an ADC2 channel", self.co2_adc_channel));
+        check(!self.fw_tag.is_some_and(|tag| tag.trim().is_empty()), "fw_tag must not be blank".to_string());
+
+        for template in [OTA_CHUNK_REQUEST_TOPIC_TEMPLATE, OTA_CHUNK_RESPONSE_TOPIC_TEMPLATE] {
+            if let Err(e) = validate_chunk_topic_template(template) {
+                check(false, e.to_string());
+            }
+        }
+        check(OTA_CHUNK_SIZE + CHUNK_SIZE_MARGIN <= MQTT_BUFFER_SIZE as usize,
+            format!("OTA_CHUNK_SIZE plus CHUNK_SIZE_MARGIN exceeds the {} byte MQTT buffer", MQTT_BUFFER_SIZE));
+        check(CHUNK_TIMEOUT_FLOOR_MS <= CHUNK_TIMEOUT_CEILING_MS, "CHUNK_TIMEOUT_FLOOR_MS exceeds CHUNK_TIMEOUT_CEILING_MS".to_string());
+        check(
+            PUBLISH_FAILURES_BEFORE_MQTT_RECONNECT < PUBLISH_FAILURES_BEFORE_WIFI_RECONNECT
+                && PUBLISH_FAILURES_BEFORE_WIFI_RECONNECT < PUBLISH_FAILURES_BEFORE_REBOOT,
+            "publish failure thresholds must increase from MQTT reconnect to WiFi reconnect to reboot".to_string()
+        );
+        check(HEALTH_HEAP_CRITICAL_BYTES < HEALTH_HEAP_DEGRADED_BYTES,
+            "HEALTH_HEAP_CRITICAL_BYTES must be below HEALTH_HEAP_DEGRADED_BYTES".to_string());
+        check(CHIP_TEMP_RANGE_MIN_C < CHIP_TEMP_RANGE_MAX_C, "CHIP_TEMP_RANGE_MIN_C must be below CHIP_TEMP_RANGE_MAX_C".to_string());
+        check(CO2_EMA_ALPHA > 0.0 && CO2_EMA_ALPHA <= 1.0, format!("CO2_EMA_ALPHA {} is outside (0, 1]", CO2_EMA_ALPHA));
+        check(TELEMETRY_BATCH_SIZE > 0 && BOOT_BACKLOG_CAPACITY > 0 && SENSOR_TASK_QUEUE_CAPACITY > 0,
+            "telemetry batch, boot backlog and sensor queue sizes must be positive".to_string());
+        check(BOOT_BACKLOG_FLUSH_CHUNK > 0 && BOOT_BACKLOG_FLUSH_CHUNK * TELEMETRY_READING_MAX_BYTES <= MQTT_BUFFER_SIZE as usize,
+            format!("BOOT_BACKLOG_FLUSH_CHUNK readings of {} bytes do not fit the {} byte MQTT buffer", TELEMETRY_READING_MAX_BYTES, MQTT_BUFFER_SIZE));
+        check(BME280_MODE != Bme280Mode::Normal || bme280_standby_code(BME280_NORMAL_STANDBY_MS).is_some(),
+            format!("BME280_NORMAL_STANDBY_MS {} is not a standby time the BME280 supports", BME280_NORMAL_STANDBY_MS));
+        for rule in ALARM_RULES {
+            let ordered = match rule.direction {
+                AlarmDirection::Above => rule.warning < rule.critical,
+                AlarmDirection::Below => rule.warning > rule.critical,
+            };
+            check(ordered && rule.hysteresis >= 0.0,
+                format!("alarm on {}: warning must come before critical and hysteresis must not be negative", rule.metric.key()));
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(ConfigError { problems }) }
+    }
 }
 
 #[no_mangle]
@@ -735,189 +5516,571 @@ fn main() -> i32 {
     esp_idf_sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
     info!("Starting BME280 + WiFi + CO2 ADC + MQTT application");
+    info!("Firmware build: {} ({})", FW_BUILD_TIMESTAMP, FW_GIT_HASH);
+
+    if let Err(e) = DEFAULT_CONFIG.validate() {
+        for problem in &e.problems {
+            error!("Invalid configuration: {}", problem);
+        }
+        error!("Refusing to start: {}", e);
+        return -1;
+    }
+    let firmware_response_subscription = chunk_topic_subscription(OTA_CHUNK_RESPONSE_TOPIC_TEMPLATE);
 
     let peripherals = Peripherals::take().unwrap();
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let nvs = EspDefaultNvsPartition::take().unwrap();
+    let mut lifetime_stats = LifetimeStats::open(nvs.clone());
+    let mut device_location = DeviceLocation::open(nvs.clone());
+    let mut broker_config = BrokerConfig::open(nvs.clone());
     let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs)).unwrap(),
+        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone())).unwrap(),
         sys_loop,
     ).unwrap();
 
-    if let Err(e) = connect_wifi(&mut wifi) {
-        error!("Failed to connect to WiFi: {:?}", e);
-        return -1;
+    if WIFI_CONNECT_NON_BLOCKING {
+        if let Err(e) = start_wifi_connect(&mut wifi) {
+            error!("Failed to start WiFi connect, will retry from the main loop: {:?}", e);
+        }
+    } else {
+        let mut wifi_attempt = 1;
+        while let Err(e) = connect_wifi(&mut wifi) {
+            error!("Failed to connect to WiFi (attempt {}/{}): {:?}", wifi_attempt, WIFI_CONNECT_ATTEMPTS, e);
+            if wifi_attempt >= WIFI_CONNECT_ATTEMPTS {
+                return -1;
+            }
+            wifi_attempt += 1;
+            LifetimeCounter::WifiReconnects.increment();
+            if let Err(e) = wifi.disconnect() {
+                error!("Failed to reset WiFi connection: {:?}", e);
+            }
+        }
     }
 
-    let scl = peripherals.pins.gpio9;
-    let sda = peripherals.pins.gpio8;
-    let i2c = I2cDriver::new(
-        peripherals.i2c0,
-        sda,
-        scl,
-        &esp_idf_hal::i2c::I2cConfig::new().baudrate(100.kHz().into())
-    ).unwrap();
-    let mut bme280 = BME280::new_primary(i2c);
-    let mut delay = Ets;
+    let mut time_sync = TimeSync::start();
+    // Batched readings carry their own timestamps, so wait for wall-clock time
+    if TELEMETRY_BATCH_SIZE > 1 && !WIFI_CONNECT_NON_BLOCKING {
+        if let Err(e) = time_sync.wait_for_sync(30) {
+            error!("{:?}, batched timestamps will be unreliable until it completes", e);
+        }
+    }
 
-    if let Err(e) = bme280.init(&mut delay) {
-        error!("Failed to init BME280: {:?}", e);
-        return -1;
+    if co2_warming_up() {
+        info!("CO2 sensor warming up, readings withheld for {} s after boot", CO2_WARMUP_MS / 1000);
     }
 
     info!("Connecting to MQTT broker...");
-    let mut ota_manager = Box::new(OtaManager::new());
-    let ota_manager_ptr = &mut *ota_manager as *mut OtaManager;
-
-    let mqtt_client = match SimpleMqttClient::new(
-        "mqtt://mqtt.thingsboard.cloud:1883",
-        "nazwana",
-        "akuandik08",
-        "eprtrartn5tpdw7oq38f",
-        ota_manager_ptr
-    ) {
-        Ok(client) => {
-            info!("Connected to ThingsBoard MQTT broker");
-            if let Err(e) = client.subscribe("v1/devices/me/attributes/response/+") {
-                error!("Failed to subscribe to OTA response: {:?}", e);
-            }
-            if let Err(e) = client.subscribe("v1/devices/me/attributes") {
-                error!("Failed to subscribe to attributes: {:?}", e);
-            }
-            if let Err(e) = client.subscribe("v2/fw/response/+/chunk/+") {
-                error!("Failed to subscribe to firmware response: {:?}", e);
+    let ota_nvs = match EspNvs::new(nvs.clone(), OTA_NVS_NAMESPACE, true) {
+        Ok(ota_nvs) => Some(ota_nvs),
+        Err(e) => {
+            error!("Failed to open OTA NVS namespace: {:?}", e);
+            None
+        }
+    };
+    let ota_event_log = OtaEventLog::open();
+    let mut ota_manager = OtaManager::new(ota_nvs, ota_event_log);
+    let mut mqtt_context = MqttContext::new();
+    if OTA_ENABLED {
+        mqtt_context.register_topic_handler(OTA_RESPONSE_SUBSCRIPTION, on_attribute_response, OTA_RESPONSE_QOS);
+        mqtt_context.register_topic_handler(ATTRIBUTES_TOPIC, on_attribute_update, ATTRIBUTES_QOS);
+        mqtt_context.register_topic_handler(&firmware_response_subscription, on_firmware_response, FIRMWARE_RESPONSE_QOS);
+    } else {
+        info!("OTA disabled, firmware topics are not subscribed");
+    }
+    mqtt_context.register_topic_handler(RPC_REQUEST_SUBSCRIPTION, on_rpc_request, RPC_REQUEST_QOS);
+    // Leaked so the event handler's reference stays valid for the whole run, across client restarts
+    let mqtt_context: &'static MqttContext = Box::leak(Box::new(mqtt_context));
+
+    let mqtt_client_id = mqtt_client_id();
+    info!("MQTT client id: {}", mqtt_client_id);
+    let mut mqtt_attempt = 1;
+    let mut mqtt_backoff_ms = MQTT_CONNECT_BACKOFF_MS;
+    let mqtt_client = loop {
+        match SimpleMqttClient::new(
+            &broker_config.settings.url,
+            &broker_config.settings.username,
+            &broker_config.settings.password,
+            &mqtt_client_id,
+            mqtt_context as *const MqttContext
+        ) {
+            Ok(client) => {
+                info!("Connected to ThingsBoard MQTT broker");
+                mqtt_context.subscribe_all(&client);
+                break client;
+            },
+            Err(e) => {
+                error!("Failed to connect to MQTT (attempt {}/{}): {:?}", mqtt_attempt, MQTT_CONNECT_ATTEMPTS, e);
+                if mqtt_attempt >= MQTT_CONNECT_ATTEMPTS {
+                    if broker_config.on_trial {
+                        broker_config.revert();
+                    }
+                    error!("MQTT connection attempts exhausted, rebooting");
+                    unsafe { esp_restart(); }
+                }
+                info!("Retrying MQTT connection in {} ms", mqtt_backoff_ms);
+                unsafe { vTaskDelay(ms_to_ticks(mqtt_backoff_ms)); }
+                mqtt_attempt += 1;
+                mqtt_backoff_ms = (mqtt_backoff_ms * 2).min(MQTT_CONNECT_BACKOFF_MAX_MS);
             }
-            client
-        },
+        }
+    };
+
+    // Startup publishes are queued until the broker confirms the session
+    let mut boot_telemetry_pending = true;
+    let mut firmware_info_pending = OTA_ENABLED;
+    let mut ota_disabled_report_pending = !OTA_ENABLED;
+    let mut firmware_refresh_after_resubscribe = false;
+
+    // Pins come from DEFAULT_CONFIG by number; nothing else in the firmware claims them
+    let (sda, scl) = unsafe { (AnyIOPin::new(DEFAULT_CONFIG.i2c_sda_gpio), AnyIOPin::new(DEFAULT_CONFIG.i2c_scl_gpio)) };
+    // The sensors come up after the broker connection, so a hardware fault can still be reported
+    let mut i2c = match I2cDriver::new(
+        peripherals.i2c0,
+        sda,
+        scl,
+        &esp_idf_hal::i2c::I2cConfig::new().baudrate(DEFAULT_CONFIG.i2c_baudrate_khz.kHz().into())
+    ) {
+        Ok(i2c) => i2c,
+        Err(e) => {
+            let e = anyhow!("Failed to install I2C driver on SDA GPIO{} / SCL GPIO{}: {:?}", DEFAULT_CONFIG.i2c_sda_gpio, DEFAULT_CONFIG.i2c_scl_gpio, e);
+            error!("{:?}", e);
+            report_startup_failure(&mqtt_client, mqtt_context, "i2c", &e);
+            return -1;
+        }
+    };
+    let i2c_devices = scan_i2c_bus(&mut i2c);
+    let mut bme280 = BME280::new_primary(i2c);
+    let bme280_settings = BME280_DEFAULT_SETTINGS;
+
+    let bme280_init = init_bme280(&mut bme280, &bme280_settings).and_then(|_| match BME280_MODE {
+        Bme280Mode::Forced => Ok(None),
+        Bme280Mode::Normal => Bme280Calibration::read().map(Some),
+    });
+    let bme280_calibration = match bme280_init {
+        Ok(calibration) => calibration,
         Err(e) => {
-            error!("Failed to connect to MQTT: {:?}", e);
+            error!("Failed to init BME280 at 0x{:02x}: {:?}", BME280_ADDRESSES[0], e);
+            if i2c_devices.contains(&BME280_ADDRESSES[1]) {
+                error!("A device responded at 0x{:02x}; the sensor may be strapped to the secondary address", BME280_ADDRESSES[1]);
+            }
+            report_startup_failure(&mqtt_client, mqtt_context, "sensor", &e);
             return -1;
         }
     };
+    match BME280_MODE {
+        Bme280Mode::Forced => info!("BME280 in forced mode, up to {} ms per conversion", bme280_settings.measurement_time_ms()),
+        Bme280Mode::Normal => info!("BME280 in normal mode, {} ms standby between conversions", BME280_NORMAL_STANDBY_MS),
+    }
 
-    if let Err(e) = ota_manager.request_firmware_info(mqtt_client.client) {
-        error!("Failed to request firmware info: {:?}", e);
+    let mut gas_sensors = GasSensorArray::init();
+
+    if let Err(e) = validate_adc_config() {
+        error!("Invalid CO2 ADC configuration: {:?}", e);
+        return -1;
     }
 
-    unsafe {
-        let init_cfg = adc_oneshot_unit_init_cfg_t {
-            unit_id: adc_unit_t_ADC_UNIT_2,
-            clk_src: soc_periph_adc_rtc_clk_src_t_ADC_RTC_CLK_SRC_DEFAULT,
-            ..Default::default()
-        };
-        let mut adc2_handle: adc_oneshot_unit_handle_t = core::ptr::null_mut();
-        let res = adc_oneshot_new_unit(&init_cfg, &mut adc2_handle);
-        if res != ESP_OK {
-            error!("Failed to init ADC unit");
+    let co2_adc = match Co2Adc::new() {
+        Ok(adc) => adc,
+        Err(e) => {
+            error!("{:?}", e);
             return -1;
         }
+    };
+    let chip_temperature = ChipTemperature::open();
 
-        let chan_cfg = adc_oneshot_chan_cfg_t {
-            atten: adc_atten_t_ADC_ATTEN_DB_11,
-            bitwidth: adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
-        };
-        let res = adc_oneshot_config_channel(
-            adc2_handle,
-            adc_channel_t_ADC_CHANNEL_1,
-            &chan_cfg,
-        );
-        if res != ESP_OK {
-            error!("Failed to config ADC channel");
-            return -1;
+    // Leaked so the sensor task and the status page can keep a reference for the whole run
+    let sensor_hub: &'static SensorHub = Box::leak(Box::new(SensorHub::new(
+        SensorState {
+            bme280,
+            bme280_settings,
+            bme280_calibration,
+            co2_adc,
+            co2_fault_detector: Co2FaultDetector::new(),
+            co2_adc_recovery: Co2AdcRecovery::new(),
+            co2_filter: EmaFilter::new(CO2_EMA_ALPHA),
+            chip_temperature,
+            i2c_bus_monitor: I2cBusMonitor::new(),
+        },
+        (device_location.latitude, device_location.longitude)
+    )));
+    let sensor_task_running = SENSOR_TASK_ENABLED && match sensor_hub.spawn_task() {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{:?}, sampling from the main loop instead", e);
+            false
         }
+    };
+
+    #[cfg(feature = "http-status")]
+    let _status_server = match StatusServer::start(sensor_hub, ota_manager.status_snapshot) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            error!("Failed to start HTTP status page: {:?}", e);
+            None
+        }
+    };
 
+    unsafe {
         let mut counter = 0;
-        let mut ota_check_counter = 0;
+        let mut last_firmware_check_tick = xTaskGetTickCount();
+        let mut telemetry_boost = TelemetryBoost::new();
+        let mut connectivity = ConnectivityMonitor::new();
+        let mut publish_escalation = PublishFailureEscalation::new();
+        let mut firmware_validation = FirmwareValidation::start();
+        let mut telemetry_batch = TelemetryBatch::new();
+        let mut change_filter = TelemetryChangeFilter::new();
+        let mut boot_backlog = BootBacklog::new();
+        let mut last_wifi_retry = xTaskGetTickCount();
+        let mut last_sample_tick = xTaskGetTickCount();
+        let mut sampling_paused = false;
+        let mut alarm_monitor = AlarmMonitor::new();
         loop {
             counter += 1;
-            ota_check_counter += 1;
+            time_sync.poll();
+            let mqtt_connected = mqtt_context.is_connected();
 
-            if ota_manager.ota_state == OtaState::Downloading {
-                if let Err(e) = ota_manager.check_chunk_timeout(mqtt_client.client) {
-                    error!("Failed to check chunk timeout: {:?}", e);
+            // Blocking mode only retries here after the connectivity check has dropped the link
+            if (WIFI_CONNECT_NON_BLOCKING || connectivity.reconnect_due())
+                && !wifi.is_connected().unwrap_or(false)
+                && ticks_elapsed(xTaskGetTickCount(), last_wifi_retry).unwrap_or(u32::MAX) >= ms_to_ticks(WIFI_RECONNECT_INTERVAL_MS)
+            {
+                last_wifi_retry = xTaskGetTickCount();
+                LifetimeCounter::WifiReconnects.increment();
+                if let Err(e) = start_wifi_connect(&mut wifi) {
+                    error!("WiFi reconnect failed: {:?}", e);
                 }
-                if ota_manager.telemetry_counter == 0 {
-                    let measurements = match bme280.measure(&mut delay) {
-                        Ok(m) => m,
-                        Err(e) => {
-                            error!("BME280 read error: {:?}", e);
-                            vTaskDelay(ms_to_ticks(1000));
-                            continue;
-                        }
-                    };
+            }
+            if WIFI_CONNECT_NON_BLOCKING && mqtt_connected {
+                if let Err(e) = boot_backlog.flush(&mqtt_client, &time_sync) {
+                    error!("Failed to publish held readings: {:?}", e);
+                }
+            }
+
+            if connectivity.check_due() && wifi.is_connected().unwrap_or(false) {
+                if !connectivity.check(&wifi) && connectivity.reconnect_due() {
+                    error!("No connectivity despite WiFi reporting connected, reconnecting WiFi and MQTT");
+                    LifetimeCounter::WifiReconnects.increment();
+                    last_wifi_retry = xTaskGetTickCount();
+                    if let Err(e) = wifi.disconnect() {
+                        error!("Failed to drop WiFi connection: {:?}", e);
+                    }
+                    if let Err(e) = start_wifi_connect(&mut wifi) {
+                        error!("WiFi reconnect failed: {:?}", e);
+                    }
+                    if let Err(e) = mqtt_client.reconnect() {
+                        error!("{:?}", e);
+                    }
+                }
+                if mqtt_connected {
+                    if let Err(e) = publish_telemetry(&mqtt_client, &connectivity.to_json()) {
+                        error!("Failed to send connectivity status: {:?}", e);
+                    }
+                }
+            }
+
+            match publish_escalation.poll() {
+                PublishRecovery::None => {}
+                PublishRecovery::ReconnectMqtt => {
+                    info!("Forcing an MQTT reconnect");
+                    if let Err(e) = mqtt_client.reconnect() {
+                        error!("{:?}", e);
+                    }
+                }
+                PublishRecovery::ReconnectWifi => {
+                    info!("Forcing a WiFi and MQTT reconnect");
+                    LifetimeCounter::WifiReconnects.increment();
+                    last_wifi_retry = xTaskGetTickCount();
+                    if let Err(e) = wifi.disconnect() {
+                        error!("Failed to drop WiFi connection: {:?}", e);
+                    }
+                    if let Err(e) = start_wifi_connect(&mut wifi) {
+                        error!("WiFi reconnect failed: {:?}", e);
+                    }
+                    if let Err(e) = mqtt_client.reconnect() {
+                        error!("{:?}", e);
+                    }
+                }
+                PublishRecovery::Reboot => {
+                    error!("Telemetry still failing after reconnecting, rebooting");
+                    lifetime_stats.flush();
+                    sensor_hub.release();
+                    vTaskDelay(ms_to_ticks(1000));
+                    esp_restart();
+                }
+            }
+
+            if mqtt_connected && mqtt_context.take_device_info_pending() {
+                if let Err(e) = send_device_info(&mqtt_client, &ota_manager) {
+                    error!("Failed to send device info: {:?}", e);
+                }
+            }
+
+            if mqtt_connected && boot_telemetry_pending {
+                boot_telemetry_pending = false;
+                if let Err(e) = send_boot_telemetry(&mqtt_client, &ota_manager, &i2c_devices) {
+                    error!("Failed to send boot telemetry: {:?}", e);
+                }
+            }
+
+            if mqtt_connected && mqtt_context.take_reconnected() {
+                info!("MQTT session re-established, restoring subscriptions");
+                mqtt_context.subscribe_all(&mqtt_client);
+                firmware_refresh_after_resubscribe = OTA_ENABLED && MQTT_REFRESH_FIRMWARE_INFO_ON_RECONNECT;
+            }
+
+            if mqtt_connected {
+                for topic in mqtt_context.retry_unacknowledged_subscriptions(&mqtt_client) {
+                    if let Err(e) = publish_telemetry(&mqtt_client, &json!({ "subscription_error": topic })) {
+                        error!("Failed to report subscription error: {:?}", e);
+                    }
+                }
+            }
+
+            if mqtt_connected && firmware_refresh_after_resubscribe && mqtt_context.subscriptions_confirmed() {
+                firmware_refresh_after_resubscribe = false;
+                if ota_manager.ota_state.is_active() {
+                    info!("Reconnected during an update, not re-requesting firmware info");
+                } else {
+                    info!("Subscriptions confirmed after reconnect, checking for firmware pushed while offline");
+                    firmware_info_pending = true;
+                }
+            }
+
+            if mqtt_connected && ota_disabled_report_pending {
+                let payload = json!({ "fw_state": ota_manager.ota_state_str() }).to_string();
+                match mqtt_client.publish(OTA_PROGRESS_TOPIC.unwrap_or(OTA_TELEMETRY_TOPIC), &payload) {
+                    Ok(()) => ota_disabled_report_pending = false,
+                    Err(e) => error!("Failed to report disabled OTA: {:?}", e),
+                }
+            }
+
+            if mqtt_connected && firmware_info_pending {
+                match ota_manager.request_firmware_info(mqtt_client.client) {
+                    Ok(()) => firmware_info_pending = false,
+                    Err(e) => error!("Failed to request firmware info: {:?}", e),
+                }
+            }
 
-                    let mut value: i32 = 0;
-                    let res = adc_oneshot_read(adc2_handle, adc_channel_t_ADC_CHANNEL_1, &mut value);
-                    let co2_ppm = if res == ESP_OK {
-                        adc_to_ppm(value)
+            for message in mqtt_context.take_ota_messages() {
+                ota_manager.handle_message(message, mqtt_client.client);
+            }
+
+            if ota_manager.restart_pending {
+                if let Err(e) = telemetry_batch.flush(&mqtt_client) {
+                    error!("Failed to flush telemetry batch before restart: {:?}", e);
+                }
+                lifetime_stats.flush();
+                if let Err(e) = send_device_info(&mqtt_client, &ota_manager) {
+                    error!("Failed to send updated device info: {:?}", e);
+                }
+                if let Some(msg_id) = ota_manager.final_report_msg_id {
+                    if mqtt_context.wait_for_publish_ack(msg_id, OTA_FINAL_REPORT_ACK_WAIT_MS) {
+                        info!("UPDATED report acknowledged by the broker");
                     } else {
-                        error!("ADC read error");
-                        0.0
-                    };
+                        warn!("UPDATED report not acknowledged within {} ms, restarting anyway", OTA_FINAL_REPORT_ACK_WAIT_MS);
+                    }
+                }
+                info!("Restarting into new firmware...");
+                sensor_hub.release();
+                vTaskDelay(ms_to_ticks(1000));
+                esp_restart();
+            }
 
-                    info!("=== Reading {} ===", counter);
-                    info!("Temperature: {:.2} °C", measurements.temperature);
-                    info!("Humidity: {:.2} %", measurements.humidity);
-                    info!("Pressure: {:.2} hPa", measurements.pressure / 100.0);
-                    info!("CO2 Concentration: {:.2} ppm", co2_ppm);
-
-                    if let Err(e) = send_telemetry(
-                        &mqtt_client,
-                        measurements.temperature,
-                        measurements.humidity,
-                        measurements.pressure,
-                        co2_ppm
-                    ) {
-                        error!("Failed to send telemetry: {:?}", e);
+            for request in mqtt_context.take_rpc_requests() {
+                let result = handle_rpc_request(
+                    &request, sensor_hub, &ota_manager, &time_sync, &mut telemetry_boost, &mut lifetime_stats,
+                    &mut device_location, &mut broker_config
+                );
+                let response = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        error!("RPC {} failed: {:?}", request.method, e);
+                        json!({ "error": e.to_string() })
                     }
+                };
+                let response_topic = format!("{}{}", RPC_RESPONSE_TOPIC, request.request_id);
+                if let Err(e) = mqtt_client.publish(&response_topic, &response.to_string()) {
+                    error!("Failed to send RPC response: {:?}", e);
+                }
+            }
+
+            if broker_config.on_trial && mqtt_connected {
+                broker_config.confirm();
+            } else if broker_config.trial_expired() {
+                broker_config.revert();
+                broker_config.restart_pending = true;
+            }
+            // The setBroker acknowledgment above still goes out on the current broker before the reboot
+            if broker_config.restart_pending {
+                info!("Restarting to apply MQTT broker settings...");
+                sensor_hub.release();
+                vTaskDelay(ms_to_ticks(1000));
+                esp_restart();
+            }
+
+            // During a download the loop runs every 100 ms to service chunks and only samples when due
+            let downloading = ota_manager.ota_state == OtaState::Downloading;
+            let sample_due = if downloading {
+                if let Err(e) = ota_manager.check_chunk_timeout(mqtt_client.client) {
+                    error!("Failed to check chunk timeout: {:?}", e);
+                }
+                if let Err(e) = ota_manager.poll_http_download(mqtt_client.client) {
+                    error!("HTTP firmware download failed: {:?}", e);
+                }
+                if SENSOR_PAUSE_DURING_FLASH_WRITES {
+                    if !sampling_paused {
+                        info!("Pausing sensor sampling while firmware is written to flash");
+                        sampling_paused = true;
+                    }
+                    false
+                } else if SENSOR_TELEMETRY_DURING_OTA {
+                    ticks_elapsed(xTaskGetTickCount(), last_sample_tick).unwrap_or(u32::MAX) >= ms_to_ticks(DEFAULT_CONFIG.sensor_sample_interval_ms)
+                } else {
+                    ota_manager.telemetry_counter == 0
                 }
-                vTaskDelay(ms_to_ticks(100));
             } else {
-                if ota_check_counter >= 6 {
-                    ota_check_counter = 0;
-                    if let Err(e) = ota_manager.request_firmware_info(mqtt_client.client) {
-                        error!("Failed to request firmware info: {:?}", e);
+                if sampling_paused {
+                    info!("Resuming sensor sampling");
+                    sampling_paused = false;
+                }
+                if OTA_ENABLED && ticks_elapsed(xTaskGetTickCount(), last_firmware_check_tick).unwrap_or(u32::MAX) >= ms_to_ticks(ota_manager.firmware_info_poll_interval_ms()) {
+                    last_firmware_check_tick = xTaskGetTickCount();
+                    if mqtt_connected {
+                        if let Err(e) = ota_manager.request_firmware_info(mqtt_client.client) {
+                            error!("Failed to request firmware info: {:?}", e);
+                        }
+                    } else {
+                        firmware_info_pending = true;
                     }
                 }
+                true
+            };
+
+            sensor_hub.configure(
+                telemetry_boost.interval_ms(), sampling_paused, (device_location.latitude, device_location.longitude)
+            );
+            // The sensor task queues its readings; otherwise the sample is taken here
+            let samples = if !sample_due {
+                Vec::new()
+            } else {
+                last_sample_tick = xTaskGetTickCount();
+                if sensor_task_running { sensor_hub.take_samples() } else { alloc::vec![sensor_hub.sample()] }
+            };
 
-                let measurements = match bme280.measure(&mut delay) {
-                    Ok(m) => m,
+            let mut sample_failed = false;
+            for sample in samples {
+                let reading = match sample {
+                    Ok(reading) => reading,
                     Err(e) => {
-                        error!("BME280 read error: {:?}", e);
-                        vTaskDelay(ms_to_ticks(1000));
+                        error!("{:?}", e);
+                        sample_failed = true;
+                        // Without a reading there is no telemetry to carry the health, so it goes out alone
+                        if mqtt_connected {
+                            let co2_faulted = sensor_hub.with_state(|state| state.co2_fault_detector.is_faulted() || state.co2_adc_recovery.failed)
+                                .unwrap_or(false);
+                            let status = SubsystemStatus::collect(
+                                wifi.is_connected().unwrap_or(false), mqtt_connected, false,
+                                !co2_faulted, ota_manager.ota_state.is_failed()
+                            );
+                            let mut payload = status.to_json();
+                            if let (true, Value::Object(map), Value::Object(error)) = (SENSOR_ERROR_TELEMETRY, &mut payload, sensor_error_json("bme280", &e.to_string())) {
+                                map.extend(error);
+                            }
+                            if let Err(e) = publish_telemetry(&mqtt_client, &payload) {
+                                error!("Failed to send health status: {:?}", e);
+                            }
+                        }
                         continue;
                     }
                 };
 
-                let mut value: i32 = 0;
-                let res = adc_oneshot_read(adc2_handle, adc_channel_t_ADC_CHANNEL_1, &mut value);
-                let co2_ppm = if res == ESP_OK {
-                    adc_to_ppm(value)
-                } else {
-                    error!("ADC read error");
-                    0.0
-                };
+                reading.log(counter);
 
-                info!("=== Reading {} ===", counter);
-                info!("Temperature: {:.2} °C", measurements.temperature);
-                info!("Humidity: {:.2} %", measurements.humidity);
-                info!("Pressure: {:.2} hPa", measurements.pressure / 100.0);
-                info!("CO2 Concentration: {:.2} ppm", co2_ppm);
+                if let (true, true, Some(error)) = (SENSOR_ERROR_TELEMETRY, mqtt_connected, &reading.co2_read_error) {
+                    if let Err(e) = publish_telemetry(&mqtt_client, &sensor_error_json("co2_adc", error)) {
+                        error!("Failed to send sensor error: {:?}", e);
+                    }
+                }
+
+                for change in alarm_monitor.update(&reading) {
+                    info!(
+                        "Alarm {}: {} -> {} at {:.2}",
+                        change.rule.metric.key(), change.from.as_str(), change.to.as_str(), change.value
+                    );
+                    if mqtt_connected {
+                        if let Err(e) = publish_alarm(&mqtt_client, &change) {
+                            error!("Failed to send alarm: {:?}", e);
+                        }
+                    }
+                }
 
+                // The gas sensors share the I2C bus with the BME280
+                let mut extra_values = sensor_hub.with_state(|_| gas_sensors.read_all()).unwrap_or_default();
+                let status = SubsystemStatus::collect(
+                    wifi.is_connected().unwrap_or(false), mqtt_connected, true,
+                    !reading.co2_sensor_fault, ota_manager.ota_state.is_failed()
+                );
+                if let Value::Object(health) = status.to_json() {
+                    extra_values.extend(health);
+                }
                 if let Err(e) = send_telemetry(
                     &mqtt_client,
-                    measurements.temperature,
-                    measurements.humidity,
-                    measurements.pressure,
-                    co2_ppm
+                    &mut telemetry_batch,
+                    &mut boot_backlog,
+                    &mut change_filter,
+                    &reading,
+                    &time_sync,
+                    extra_values
                 ) {
                     error!("Failed to send telemetry: {:?}", e);
                 }
+            }
+            // A failed read in the main loop is retried after a second instead of the full interval
+            if sample_failed && !sensor_task_running {
+                vTaskDelay(ms_to_ticks(1000));
+                continue;
+            }
+
+            if downloading {
+                vTaskDelay(ms_to_ticks(100));
+            } else {
+                vTaskDelay(ms_to_ticks(telemetry_boost.interval_ms()));
+            }
+
+            if telemetry_batch.is_due() {
+                if let Err(e) = telemetry_batch.flush(&mqtt_client) {
+                    error!("Failed to flush telemetry batch: {:?}", e);
+                }
+            }
+
+            if firmware_validation.pending {
+                firmware_validation.poll();
+                if mqtt_connected && (!firmware_validation.pending || firmware_validation.report_due()) {
+                    if let Err(e) = publish_telemetry(&mqtt_client, &firmware_validation.to_json()) {
+                        error!("Failed to send firmware validation progress: {:?}", e);
+                    }
+                }
+            }
+
+            if lifetime_stats.flush_due() {
+                lifetime_stats.flush();
+                if mqtt_connected {
+                    if let Err(e) = publish_telemetry(&mqtt_client, &lifetime_stats_json()) {
+                        error!("Failed to send lifetime statistics: {:?}", e);
+                    }
+                }
+            }
 
-                vTaskDelay(ms_to_ticks(5000));
+            if mqtt_connected {
+                if let Some(event) = sensor_hub.with_state(|state| state.i2c_bus_monitor.pending_event.take()).flatten() {
+                    if let Err(e) = publish_telemetry(&mqtt_client, &event) {
+                        error!("Failed to send I2C bus recovery event: {:?}", e);
+                        sensor_hub.with_state(|state| state.i2c_bus_monitor.pending_event = Some(event));
+                    }
+                }
             }
 
-            if ota_manager.ota_state != OtaState::Idle {
+            if mqtt_connected && (ota_manager.ota_state.is_active() || ota_manager.ota_state.is_terminal()) {
                 if let Err(e) = ota_manager.send_ota_telemetry(mqtt_client.client) {
                     error!("Failed to send OTA telemetry: {:?}", e);
                 }
@@ -0,0 +1,84 @@
+// Choices behind the compile-time settings in main.rs. A setting constructs only the variant it selects, so the
+// others are never built in a given firmware and would otherwise trip dead_code.
+#![allow(dead_code)]
+
+// TELEMETRY_ENCODING
+#[derive(Clone, Copy, PartialEq)]
+pub enum TelemetryEncoding {
+    Json,
+    Cbor,
+}
+
+// UNKNOWN_TOPIC_POLICY
+#[derive(Clone, Copy, PartialEq)]
+pub enum UnknownTopicPolicy {
+    Log,
+    Ignore,
+    Report,
+}
+
+// GAS_SENSORS
+#[derive(Clone, Copy, PartialEq)]
+pub enum GasSensorType {
+    Scd41,
+    Sgp30,
+}
+
+// BME280_MODE
+#[derive(Clone, Copy, PartialEq)]
+pub enum Bme280Mode {
+    Forced,
+    Normal,
+}
+
+// OTA_CHECKSUM_MODE
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChecksumMode {
+    Streaming,
+    Buffered,
+}
+
+// ALARM_RULES
+#[derive(Clone, Copy)]
+pub enum AlarmMetric {
+    Temperature,
+    Humidity,
+    Pressure,
+    Co2Ppm,
+    ChipTemperature,
+}
+
+// ALARM_RULES
+#[derive(Clone, Copy)]
+pub enum AlarmDirection {
+    Above,
+    Below,
+}
+
+// CO2_COMPENSATION
+#[derive(Clone, Copy)]
+pub enum Co2Compensation {
+    None,
+    Quadratic { a: f32, b: f32, c: f32, d: f32, reference_humidity: f32 },
+}
+pub const CO2_COMPENSATION_MQ135: Co2Compensation =
+    Co2Compensation::Quadratic { a: 0.00035, b: 0.02718, c: 1.39538, d: 0.0018, reference_humidity: 33.0 };
+
+// BOOT_BACKLOG_FLUSH_ORDER
+pub enum BacklogFlushOrder {
+    OldestFirst,
+    NewestFirst,
+}
+
+// TELEMETRY_SINKS
+pub enum SinkFormat {
+    Json,
+    Cbor,
+}
+
+// CO2_READ_ERROR_REPORT
+pub enum Co2ReadErrorReport {
+    Null,
+    Omit,
+    Sentinel,
+}
@@ -0,0 +1,148 @@
+// Firmware version parsing and ordering, shared by downgrade protection and the image version check.
+// Accepts the project's "VX.Y" form as well as plain "X.Y.Z", with an optional prerelease suffix
+// ("V2.0-rc1") and build metadata ("1.2.3+abc", ignored for ordering).
+
+use alloc::{string::{String, ToString}, vec::Vec};
+use core::cmp::Ordering;
+
+#[derive(Debug, Clone)]
+pub struct Version {
+    // Missing trailing components count as zero, so "V2" and "V2.0" are equal
+    numbers: Vec<u32>,
+    prerelease: Option<String>,
+}
+
+// Parses versions like "V2.0", "v1.2.3", "2.0" or "V2.0-rc1"; None if any numeric component is not a number
+pub fn parse_version(version: &str) -> Option<Version> {
+    let trimmed = version.trim();
+    let unprefixed = trimmed.strip_prefix('V').or_else(|| trimmed.strip_prefix('v')).unwrap_or(trimmed);
+    let without_build = unprefixed.split('+').next().unwrap_or(unprefixed);
+    let (numeric, prerelease) = match without_build.split_once('-') {
+        Some((numeric, prerelease)) if !prerelease.is_empty() => (numeric, Some(prerelease.to_string())),
+        Some(_) => return None,
+        None => (without_build, None),
+    };
+    let numbers = numeric.split('.').map(|part| part.parse::<u32>().ok()).collect::<Option<Vec<u32>>>()?;
+    Some(Version { numbers, prerelease })
+}
+
+// True when `candidate` is strictly older than `current`, e.g. "V1.0" against "V2.0" or "V2.0-rc1" against
+// "V2.0". Unparseable versions are never treated as a downgrade.
+pub fn is_version_downgrade(candidate: &str, current: &str) -> bool {
+    matches!((parse_version(candidate), parse_version(current)), (Some(candidate), Some(current)) if candidate < current)
+}
+
+impl Version {
+    fn component(&self, i: usize) -> u32 {
+        self.numbers.get(i).copied().unwrap_or(0)
+    }
+}
+
+// Splits a prerelease identifier into its text and trailing number, so "rc2" sorts before "rc10"
+fn split_identifier(identifier: &str) -> (&str, Option<u32>) {
+    let digits_at = identifier.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let (text, digits) = identifier.split_at(digits_at);
+    (text, digits.parse().ok())
+}
+
+// Dot-separated identifiers compared in turn; a prerelease that runs out first is the older one
+fn compare_prerelease(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => {
+                let ordering = split_identifier(&a.to_ascii_lowercase()).cmp(&split_identifier(&b.to_ascii_lowercase()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.numbers.len().max(other.numbers.len());
+        for i in 0..len {
+            let ordering = self.component(i).cmp(&other.component(i));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        // A release is newer than any of its prereleases
+        match (&self.prerelease, &other.prerelease) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) => compare_prerelease(a, b),
+        }
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        parse_version(text).unwrap()
+    }
+
+    #[test]
+    fn prefix_and_missing_components_are_ignored() {
+        assert_eq!(version("V2.0"), version("2.0.0"));
+        assert_eq!(version("v2"), version("V2.0"));
+        assert!(version("v1.2") < version("V1.2.1"));
+    }
+
+    #[test]
+    fn prerelease_sorts_below_its_release() {
+        assert!(version("V2.0-rc1") < version("V2.0"));
+        assert!(version("V2.0-rc1") > version("V1.9"));
+    }
+
+    #[test]
+    fn prerelease_numbers_compare_numerically() {
+        assert!(version("V2.0-rc2") < version("V2.0-rc10"));
+        assert!(version("V2.0-alpha") < version("V2.0-beta"));
+        assert!(version("V2.0-rc") < version("V2.0-rc.1"));
+    }
+
+    #[test]
+    fn build_metadata_is_ignored() {
+        assert_eq!(version("1.2.3+abc"), version("1.2.3"));
+    }
+
+    #[test]
+    fn malformed_versions_do_not_parse() {
+        assert!(parse_version("V2.0-").is_none());
+        assert!(parse_version("V2.x").is_none());
+        assert!(parse_version("").is_none());
+    }
+
+    #[test]
+    fn downgrade_needs_both_versions_to_parse() {
+        assert!(is_version_downgrade("V1.0", "V2.0"));
+        assert!(!is_version_downgrade("V2.0", "V1.0"));
+        assert!(!is_version_downgrade("V2.0", "V2.0"));
+        assert!(!is_version_downgrade("garbage", "V2.0"));
+        assert!(!is_version_downgrade("V1.0", "garbage"));
+    }
+}